@@ -0,0 +1,150 @@
+// Protocol version negotiation happening as the very first exchange on a new websocket
+
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use warp::filters::ws::{self, WebSocket};
+
+use confroom_server::websocket::WsMessageKind;
+use crate::message::{ClientHandshake, Encoding, ServerMessage};
+
+/// The protocol version spoken by this server
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest protocol version this server still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Optional protocol features a client can ask for.
+/// Unsolicited server messages tied to a feature are only sent to clients that asked for it, see ServerMessage::required_feature
+pub const SUPPORTED_FEATURES: &[&str] = &[
+	"dataChannels",
+	"simulcastLayers",
+	"requestIds",
+	"iceRestart",
+	"sessionResume",
+	"autoSubscribe",
+	"messagePack",
+	"scores",
+	"dominantSpeaker",
+	"stats",
+	"keyFrames",
+	"bitrateCaps",
+	"migration",
+];
+
+/// Close code sent when the client didn't start with a valid Hello message
+pub const CLOSE_HANDSHAKE_FAILED: u16 = 4000;
+/// Close code sent when the client's protocol version isn't supported
+pub const CLOSE_UNSUPPORTED_VERSION: u16 = 4001;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What both ends agreed on during the handshake
+pub struct Handshake {
+	pub protocol_version: u32,
+	pub features: Vec<String>,
+	/// Encoding used for every message sent after the handshake
+	pub encoding: Encoding,
+	pub display_name: Option<String>,
+	pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+	Timeout,
+	ConnectionClosed,
+	InvalidMessage(String),
+	UnsupportedVersion(u32),
+}
+
+impl HandshakeError {
+	pub fn close_code(&self) -> u16 {
+		match self {
+			HandshakeError::UnsupportedVersion(_) => CLOSE_UNSUPPORTED_VERSION,
+			_ => CLOSE_HANDSHAKE_FAILED,
+		}
+	}
+}
+
+impl std::fmt::Display for HandshakeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			HandshakeError::Timeout => write!(f, "No Hello message received in time"),
+			HandshakeError::ConnectionClosed => write!(f, "Connection closed during the handshake"),
+			HandshakeError::InvalidMessage(e) => write!(f, "Expected a Hello message: {e}"),
+			HandshakeError::UnsupportedVersion(version) => write!(f,
+				"Unsupported protocol version {version}, supported versions are {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"),
+		}
+	}
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Waits for the client's Hello and answers with what the server supports.
+/// The handshake itself is always JSON, `default_encoding` is used if the Hello doesn't request one.
+/// When the handshake fails, the websocket is closed with a close code describing the failure.
+pub async fn negotiate(websocket: &mut WebSocket, default_encoding: Encoding) -> Result<Handshake, HandshakeError> {
+	let result = match tokio::time::timeout(HANDSHAKE_TIMEOUT, receive_hello(websocket, default_encoding)).await {
+		Ok(result) => result,
+		Err(_) => Err(HandshakeError::Timeout),
+	};
+
+	let handshake = match result {
+		Ok(handshake) => handshake,
+		Err(e) => {
+			if !matches!(e, HandshakeError::ConnectionClosed) {
+				let _ = websocket.send(ws::Message::close_with(e.close_code(), e.to_string())).await;
+			}
+			return Err(e);
+		}
+	};
+
+	let server_hello = ServerMessage::Hello {
+		protocol_version: handshake.protocol_version,
+		features: handshake.features.clone(),
+		encoding: handshake.encoding,
+	};
+
+	if websocket.send(Encoding::Json.encode(&server_hello)).await.is_err() {
+		return Err(HandshakeError::ConnectionClosed);
+	}
+
+	Ok(handshake)
+}
+
+async fn receive_hello(websocket: &mut WebSocket, default_encoding: Encoding) -> Result<Handshake, HandshakeError> {
+	while let Some(msg) = websocket.next().await {
+		let msg = match msg {
+			Ok(msg) => WsMessageKind::try_from(msg).map_err(|e| HandshakeError::InvalidMessage(e.to_string()))?,
+			Err(_) => return Err(HandshakeError::ConnectionClosed),
+		};
+
+		let text = match msg {
+			WsMessageKind::Ping(_) | WsMessageKind::Pong(_) => continue,
+			WsMessageKind::Close(_) => return Err(HandshakeError::ConnectionClosed),
+			WsMessageKind::Binary(_) => return Err(HandshakeError::InvalidMessage("binary data received".into())),
+			WsMessageKind::Text(text) => text,
+		};
+
+		let ClientHandshake::Hello { protocol_version, features, encoding, display_name, metadata } = serde_json::from_str::<ClientHandshake>(&text)
+			.map_err(|e| HandshakeError::InvalidMessage(e.to_string()))?;
+
+		if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+			return Err(HandshakeError::UnsupportedVersion(protocol_version));
+		}
+
+		// Only keep the features both ends know about
+		let features = features
+			.into_iter()
+			.filter(|feature| SUPPORTED_FEATURES.contains(&feature.as_str()))
+			.collect();
+
+		return Ok(Handshake {
+			protocol_version,
+			features,
+			encoding: encoding.unwrap_or(default_encoding),
+			display_name,
+			metadata,
+		});
+	}
+
+	Err(HandshakeError::ConnectionClosed)
+}
@@ -1,16 +1,22 @@
 mod message;
+mod handshake;
 mod room;
+mod media_policy;
 mod participant;
 mod rooms_registry;
+mod sessions_registry;
 mod monitor_dispatch;
 mod security;
 mod sfu_server;
 mod router_dispatch;
 
 use monitor_dispatch::MonitorDispatch;
-use router_dispatch::RouterDispatchConfig;
+use router_dispatch::{CodecPreset, RouterDispatchConfig, WorkerSelection, load_codecs_file};
+use participant::BitrateCaps;
 use sfu_server::{SFUServer, SFUServerConfig};
 use clap::Parser;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Clone, clap::ValueEnum, PartialEq)]
 enum MonitoringMode {
@@ -34,6 +40,24 @@ struct Args {
 	/// Mediasoup documentation recommands ~500 but it depends on the CPU
 	#[arg(short='c', long="consumers", default_value_t=500)]
 	consumers_per_worker: u32,
+	/// How many seconds a participant whose websocket dropped can take to resume its session. 0 disables resumption
+	#[arg(long="resume-grace-period", default_value_t=30)]
+	resume_grace_period: u64,
+	/// How workers are picked for new routers once all of them are running
+	#[arg(long="worker-selection", value_enum, default_value_t=WorkerSelection::LeastConsumers)]
+	worker_selection: WorkerSelection,
+	/// The highest bitrate in bps a participant can send, 0 means no limit
+	#[arg(long="max-incoming-bitrate", default_value_t=0)]
+	max_incoming_bitrate: u32,
+	/// The highest bitrate in bps a participant can receive, 0 means no limit
+	#[arg(long="max-outgoing-bitrate", default_value_t=0)]
+	max_outgoing_bitrate: u32,
+	/// The media codecs enabled on the routers, comma separated
+	#[arg(long="codecs", value_enum, value_delimiter=',', default_values_t=[CodecPreset::Opus, CodecPreset::Vp8])]
+	codecs: Vec<CodecPreset>,
+	/// A JSON file holding an array of mediasoup RtpCodecCapability, overrides --codecs
+	#[arg(long="codecs-file")]
+	codecs_file: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -47,12 +71,27 @@ async fn main() {
 		args.max_workers
 	};
 
+	let codecs = match args.codecs_file {
+		Some(path) => match load_codecs_file(&path) {
+			Ok(codecs) => codecs,
+			Err(e) => panic!("{e}"),
+		},
+		None => args.codecs.iter().map(CodecPreset::capability).collect(),
+	};
+
 	let sfu_server = SFUServer::new(SFUServerConfig {
 		port: args.port,
 		router_dispatch_config: Some(RouterDispatchConfig {
 			max_workers,
 			consumers_per_worker: args.consumers_per_worker,
-		})
+			codecs,
+			worker_selection: args.worker_selection,
+		}),
+		resume_grace_period: Duration::from_secs(args.resume_grace_period),
+		max_bitrate: BitrateCaps {
+			max_incoming_bitrate: args.max_incoming_bitrate,
+			max_outgoing_bitrate: args.max_outgoing_bitrate,
+		},
 	});
 
 	if args.monitoring_mode != MonitoringMode::NoMonitoring {
@@ -0,0 +1,71 @@
+use mediasoup::prelude::*;
+use mediasoup::rtp_parameters::MimeType;
+use serde::{Deserialize, Serialize};
+
+/// Resolution clients are asked not to exceed when sending video
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resolution {
+	pub width: u32,
+	pub height: u32,
+}
+
+/// What kind of media a room accepts, chosen when the room is created
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all="camelCase", default)]
+pub struct MediaPolicy {
+	/// Only audio producers are accepted and routers don't offer video codecs
+	pub audio_only: bool,
+	/// Only a hint sent to clients in Init, the SFU can't check the resolution of a stream
+	pub max_resolution: Option<Resolution>,
+	/// Media codecs allowed in the room, e.g. ["audio/opus", "video/H264"]. None allows every configured codec
+	pub allowed_codecs: Option<Vec<MimeType>>,
+	pub no_screen_share: bool,
+}
+
+impl MediaPolicy {
+	/// Whether a router of a room with this policy may offer this codec
+	pub fn allows_codec(&self, mime_type: MimeType) -> bool {
+		match mime_type {
+			MimeType::Video(_) if self.audio_only => false,
+			_ => self.allows_mime_type(mime_type),
+		}
+	}
+
+	/// Checks a Produce request against the policy, returns the reason of a rejection
+	pub fn check_produce(&self, kind: MediaKind, rtp_parameters: &RtpParameters, screen_share: bool) -> Result<(), String> {
+		if self.audio_only && kind == MediaKind::Video {
+			return Err("This room is audio only".into());
+		}
+
+		if self.no_screen_share && screen_share {
+			return Err("Screen sharing is not allowed in this room".into());
+		}
+
+		for codec in &rtp_parameters.codecs {
+			let mime_type = match codec {
+				RtpCodecParameters::Audio { mime_type, .. } => MimeType::Audio(*mime_type),
+				RtpCodecParameters::Video { mime_type, .. } => MimeType::Video(*mime_type),
+			};
+
+			if !self.allows_mime_type(mime_type) {
+				return Err(format!("Codec {mime_type:?} is not allowed in this room"));
+			}
+		}
+
+		Ok(())
+	}
+
+	fn allows_mime_type(&self, mime_type: MimeType) -> bool {
+		// Retransmission and redundancy formats go along with whatever media codec is allowed
+		let auxiliary = matches!(
+			mime_type,
+			MimeType::Video(MimeTypeVideo::Rtx | MimeTypeVideo::Red | MimeTypeVideo::Ulpfec) |
+			MimeType::Audio(MimeTypeAudio::Rtx | MimeTypeAudio::Red | MimeTypeAudio::Cn | MimeTypeAudio::TelephoneEvent)
+		);
+
+		match &self.allowed_codecs {
+			Some(allowed_codecs) => auxiliary || allowed_codecs.contains(&mime_type),
+			None => true,
+		}
+	}
+}
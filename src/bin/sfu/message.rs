@@ -1,10 +1,85 @@
 extern crate confroom_server as server;
-use confroom_server::uuids::{ParticipantId, RoomId};
+use confroom_server::uuids::{ParticipantId, ResumeToken, RoomId};
 use server::websocket::WsMessageKind;
 
-use crate::participant::TransportOptions;
+use crate::media_policy::MediaPolicy;
+use crate::participant::{BitrateChange, ParticipantInfo, TransportOptions};
 use mediasoup::prelude::*;
+use mediasoup::consumer::ConsumerScore;
+use mediasoup::producer::ProducerScore;
 use serde::{Deserialize, Serialize};
+use warp::filters::ws;
+
+/// How ServerMessages are encoded on a given connection.
+/// Client messages are read according to the websocket frame: text is JSON, binary is MessagePack
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all="camelCase")]
+pub enum Encoding {
+	#[default]
+	Json,
+	MessagePack,
+}
+
+impl Encoding {
+	pub fn encode(&self, msg: &ServerMessage) -> ws::Message {
+		match self {
+			Encoding::Json => match serde_json::to_string(msg) {
+				Ok(json_msg) => ws::Message::text(json_msg),
+				Err(e) => panic!("A server message couldn't be converted to JSON. This should never happen. Error: {:?}", e)
+			},
+			Encoding::MessagePack => {
+				// Human readable keeps ids as strings, the same as in JSON
+				let mut bytes = Vec::new();
+				let mut serializer = rmp_serde::Serializer::new(&mut bytes).with_struct_map().with_human_readable();
+				match msg.serialize(&mut serializer) {
+					Ok(()) => ws::Message::binary(bytes),
+					Err(e) => panic!("A server message couldn't be converted to MessagePack. This should never happen. Error: {:?}", e)
+				}
+			}
+		}
+	}
+}
+
+/// First message a client must send on a new websocket, before any ClientMessage
+#[derive(Deserialize)]
+#[serde(tag = "action")]
+pub enum ClientHandshake {
+	#[serde(rename_all="camelCase")]
+	Hello {
+		protocol_version: u32,
+		#[serde(default)]
+		features: Vec<String>,
+		/// Overrides the encoding requested through the query parameters
+		#[serde(default)]
+		encoding: Option<Encoding>,
+		/// Overrides the display name given in the query parameters
+		#[serde(default)]
+		display_name: Option<String>,
+		/// Overrides the metadata given in the query parameters
+		#[serde(default)]
+		metadata: Option<serde_json::Value>,
+	},
+}
+
+/// Identifier chosen by the client to match a request with the server message answering it
+pub type RequestId = u32;
+
+/// A ClientMessage along with an optional id echoed back on the server message answering it
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub struct ClientRequest {
+	pub request_id: Option<RequestId>,
+	#[serde(flatten)]
+	pub message: ClientMessage,
+}
+
+impl ClientRequest {
+	/// Reads a MessagePack client request, ids being strings like in JSON
+	pub fn from_message_pack(bytes: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
+		let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes).with_human_readable();
+		ClientRequest::deserialize(&mut deserializer)
+	}
+}
 
 #[derive(Deserialize)]
 #[serde(tag = "action")]
@@ -24,7 +99,10 @@ pub enum ClientMessage {
 	#[serde(rename_all="camelCase")]
 	Produce {
 		kind: MediaKind,
-		rtp_parameters: RtpParameters
+		rtp_parameters: RtpParameters,
+		/// Whether the producer is a screen share rather than a camera or microphone
+		#[serde(default)]
+		screen_share: bool,
 	},
 	#[serde(rename_all="camelCase")]
 	Consume {
@@ -32,42 +110,254 @@ pub enum ClientMessage {
 	},
 	#[serde(rename_all="camelCase")]
 	ConsumerResume{id: ConsumerId},
+	#[serde(rename_all="camelCase")]
+	RequestKeyFrame{consumer_id: ConsumerId},
+	#[serde(rename_all="camelCase")]
+	SetConsumerPreferredLayers {
+		id: ConsumerId,
+		spatial_layer: u8,
+		temporal_layer: Option<u8>,
+	},
+	#[serde(rename_all="camelCase")]
+	PauseProducer{id: ProducerId},
+	#[serde(rename_all="camelCase")]
+	ResumeProducer{id: ProducerId},
+	#[serde(rename_all="camelCase")]
+	CloseProducer{id: ProducerId},
+	#[serde(rename_all="camelCase")]
+	RestartIce {
+		transport_id: TransportId,
+	},
+	#[serde(rename_all="camelCase")]
+	ProduceData {
+		sctp_stream_parameters: SctpStreamParameters,
+		#[serde(default)]
+		label: String,
+		#[serde(default)]
+		protocol: String,
+	},
+	#[serde(rename_all="camelCase")]
+	ConsumeData {
+		data_producer_id: DataProducerId,
+	},
+	/// Caps the bitrates of the sender, participant_id may only be its own id until there are moderators
+	#[serde(rename_all="camelCase")]
+	SetMaxBitrate {
+		participant_id: Option<ParticipantId>,
+		#[serde(flatten)]
+		change: BitrateChange,
+	},
+	#[serde(rename_all="camelCase")]
+	GetStats {
+		target: StatsTarget,
+	},
+}
+
+/// One of the participant's own mediasoup objects to fetch the stats of
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(tag = "type", content = "id", rename_all="camelCase")]
+pub enum StatsTarget {
+	Transport(TransportId),
+	Producer(ProducerId),
+	Consumer(ConsumerId),
+}
+
+/// Stable error codes the client can branch on, the accompanying message is only meant for humans
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+	/// The client sent something that couldn't be read as a ClientMessage
+	InvalidMessage,
+	/// The client must send an Init message first
+	NotInitialized,
+	TransportConnectFailed,
+	TransportNotFound,
+	IceRestartFailed,
+	ProduceFailed,
+	/// The media policy of the room forbids this producer
+	ProduceNotAllowed,
+	ProducerNotFound,
+	PauseProducerFailed,
+	ResumeProducerFailed,
+	CannotConsume,
+	/// Every worker reached its consumer limit
+	ServerAtCapacity,
+	ConsumerNotFound,
+	ConsumerResumeFailed,
+	RequestKeyFrameFailed,
+	SetPreferredLayersFailed,
+	ProduceDataFailed,
+	CannotConsumeData,
+	StatsFailed,
+	/// The sender isn't allowed to do this
+	NotAllowed,
+	SetMaxBitrateFailed,
 }
 
 /// Internal server messages to facilitate interactions between tasks
 /// These won't be sent to the client
 pub enum Internal {
-	Close
+	/// The connection ended on purpose, the participant is leaving
+	Close,
+	/// The websocket was lost unexpectedly, the participant may resume its session
+	Disconnected,
 }
 
 /// Message types intended to be sent to the client
 #[derive(Serialize)]
 #[serde(tag = "action")]
 pub enum ServerMessage {
+	/// Answer to a ClientHandshake::Hello, features are the ones supported by both ends
+	#[serde(rename_all="camelCase")]
+	Hello {
+		protocol_version: u32,
+		features: Vec<String>,
+		encoding: Encoding,
+	},
 	#[serde(rename_all="camelCase")]
 	Init {
 		room_id: RoomId,
+		participant_id: ParticipantId,
+		/// Everyone else already in the room
+		participants: Vec<ParticipantInfo>,
+		router_rtp_capabilities: RtpCapabilitiesFinalized,
+		consumer_transport_options: TransportOptions,
+		producer_transport_options: TransportOptions,
+		resume_token: ResumeToken,
+		media_policy: MediaPolicy,
+	},
+	/// Sent instead of Init when a participant reattached to its session
+	Resumed,
+	/// Sent when the worker handling the participant died and it was moved to a new router.
+	/// The client must replace its transports with these ones, connect them, produce and consume again,
+	/// its previous producers and consumers are gone. ProducerAdded and DataProducerAdded messages follow
+	#[serde(rename_all="camelCase")]
+	Migrate {
 		router_rtp_capabilities: RtpCapabilitiesFinalized,
 		consumer_transport_options: TransportOptions,
 		producer_transport_options: TransportOptions,
 	},
-	ConnectedConsumerTransport,
-	ConnectedProducerTransport,
 	#[serde(rename_all="camelCase")]
-	ProducerAdded { participant_id: ParticipantId, producer_id: ProducerId },
+	ConnectedConsumerTransport {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		request_id: Option<RequestId>
+	},
+	#[serde(rename_all="camelCase")]
+	ConnectedProducerTransport {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		request_id: Option<RequestId>
+	},
+	#[serde(rename_all="camelCase")]
+	IceRestarted {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		request_id: Option<RequestId>,
+		transport_id: TransportId,
+		ice_parameters: IceParameters,
+	},
+	/// Sent when the server loses ICE connectivity with one of the client's transports,
+	/// the client is expected to answer with a RestartIce
+	#[serde(rename_all="camelCase")]
+	TransportDisconnected { transport_id: TransportId },
+	#[serde(rename_all="camelCase")]
+	ParticipantJoined { participant: ParticipantInfo },
+	#[serde(rename_all="camelCase")]
+	ParticipantLeft { participant_id: ParticipantId },
+	#[serde(rename_all="camelCase")]
+	ProducerAdded {
+		participant_id: ParticipantId,
+		producer_id: ProducerId,
+		display_name: Option<String>,
+		metadata: serde_json::Value,
+		/// Whether the producer is paused at the time it is announced
+		paused: bool,
+	},
 	#[serde(rename_all="camelCase")]
 	ProducerRemoved{ participant_id: ParticipantId, producer_id: ProducerId },
 	#[serde(rename_all="camelCase")]
-	Produced{id: ProducerId},
+	DominantSpeaker{ participant_id: ParticipantId, producer_id: ProducerId },
+	#[serde(rename_all="camelCase")]
+	ProducerPaused{ participant_id: ParticipantId, producer_id: ProducerId },
+	#[serde(rename_all="camelCase")]
+	ProducerResumed{ participant_id: ParticipantId, producer_id: ProducerId },
+	#[serde(rename_all="camelCase")]
+	Produced{
+		#[serde(skip_serializing_if = "Option::is_none")]
+		request_id: Option<RequestId>,
+		id: ProducerId
+	},
 	#[serde(rename_all="camelCase")]
 	Consumed{
+		#[serde(skip_serializing_if = "Option::is_none")]
+		request_id: Option<RequestId>,
 		id: ConsumerId,
 		kind: MediaKind,
 		rtp_parameters: RtpParameters,
 		producer_id: ProducerId,
+		producer_paused: bool,
+	},
+	#[serde(rename_all="camelCase")]
+	ProducerScore{ id: ProducerId, scores: Vec<ProducerScore> },
+	#[serde(rename_all="camelCase")]
+	ConsumerScore{ id: ConsumerId, score: ConsumerScore },
+	#[serde(rename_all="camelCase")]
+	ConsumerLayersChanged{ id: ConsumerId, layers: Option<ConsumerLayers> },
+	#[serde(rename_all="camelCase")]
+	DataProducerAdded { participant_id: ParticipantId, data_producer_id: DataProducerId },
+	#[serde(rename_all="camelCase")]
+	DataProducerRemoved { participant_id: ParticipantId, data_producer_id: DataProducerId },
+	#[serde(rename_all="camelCase")]
+	DataProduced{
+		#[serde(skip_serializing_if = "Option::is_none")]
+		request_id: Option<RequestId>,
+		id: DataProducerId
 	},
 	#[serde(rename_all="camelCase")]
-	Warning{message: String},
+	DataConsumed{
+		#[serde(skip_serializing_if = "Option::is_none")]
+		request_id: Option<RequestId>,
+		id: DataConsumerId,
+		data_producer_id: DataProducerId,
+		sctp_stream_parameters: Option<SctpStreamParameters>,
+		label: String,
+		protocol: String,
+	},
+	/// Answer to a ClientMessage::SetMaxBitrate with the new caps of the participant's transports, 0 means no limit
+	#[serde(rename_all="camelCase")]
+	MaxBitrateChanged {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		request_id: Option<RequestId>,
+		max_incoming_bitrate: u32,
+		max_outgoing_bitrate: u32,
+	},
+	/// Answer to a ClientMessage::GetStats, stats are the ones reported by mediasoup
+	#[serde(rename_all="camelCase")]
+	Stats {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		request_id: Option<RequestId>,
+		target: StatsTarget,
+		stats: serde_json::Value,
+	},
+	#[serde(rename_all="camelCase")]
+	Error{
+		#[serde(skip_serializing_if = "Option::is_none")]
+		request_id: Option<RequestId>,
+		code: ErrorCode,
+		message: String
+	},
+}
+
+impl ServerMessage {
+	/// The handshake feature a client must have asked for to receive this message
+	pub fn required_feature(&self) -> Option<&'static str> {
+		match self {
+			ServerMessage::TransportDisconnected { .. } => Some("iceRestart"),
+			ServerMessage::ConsumerLayersChanged { .. } => Some("simulcastLayers"),
+			ServerMessage::DataProducerAdded { .. } | ServerMessage::DataProducerRemoved { .. } => Some("dataChannels"),
+			ServerMessage::ProducerScore { .. } | ServerMessage::ConsumerScore { .. } => Some("scores"),
+			ServerMessage::DominantSpeaker { .. } => Some("dominantSpeaker"),
+			ServerMessage::Migrate { .. } => Some("migration"),
+			_ => None,
+		}
+	}
 }
 
 /// Different types of messages that can be sent by the server
@@ -70,10 +70,12 @@ impl MonitorConnection {
 					SFUEvent::RoomClosed {..}
 				)
 			},
-		    MonitoringEventCategory::Room(_listening_room_id) => {
+		    MonitoringEventCategory::Room(listening_room_id) => {
 				matches!(evt,
-					SFUEvent::ParticipantEntered {room_id:_listening_room_id, ..} |
-					SFUEvent::ParticipantLeft {room_id:_listening_room_id, ..}
+					SFUEvent::ParticipantEntered {room_id, ..} |
+					SFUEvent::ParticipantLeft {room_id, ..} |
+					SFUEvent::DominantSpeaker {room_id, ..}
+					if *room_id == listening_room_id
 				)
 			},
 		}
@@ -3,15 +3,19 @@ extern crate confroom_server as server;
 use std::collections::HashMap;
 
 use confroom_server::monitoring::SFUEvent;
-use confroom_server::uuids::ParticipantId;
+use confroom_server::uuids::{ParticipantId, ResumeToken};
 use event_listener_primitives::HandlerId;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{error::SendError, UnboundedReceiver, UnboundedSender};
 use mediasoup::prelude::*;
+use mediasoup::data_structures::IceState;
+use mediasoup::worker::RequestError;
+use mediasoup::sctp_parameters::SctpParameters;
 use warp::ws::WebSocket;
 use warp::filters::ws;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 use parking_lot::Mutex;
 
 use futures_util::{stream::{SplitSink, SplitStream}, StreamExt, SinkExt};
@@ -19,10 +23,53 @@ use futures_util::{stream::{SplitSink, SplitStream}, StreamExt, SinkExt};
 use server::websocket::WsMessageKind;
 use crate::monitor_dispatch::MonitorDispatch;
 use crate::room::Room;
+use crate::router_dispatch::RouterData;
 use crate::message::*;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
+/// Minimum delay between two score messages about the same producer or consumer
+const SCORE_THROTTLE: Duration = Duration::from_secs(2);
+
+#[derive(Default, Clone)]
+pub struct ParticipantConfig {
+	/// Consume every producer of the room as soon as possible, without waiting for Consume messages
+	pub auto_subscribe: bool,
+	/// Name shown to the other participants
+	pub display_name: Option<String>,
+	/// Arbitrary data provided by the client and forwarded as is to the other participants
+	pub metadata: serde_json::Value,
+	/// Highest bitrates the participant's transports may be set to
+	pub max_bitrate: BitrateCaps,
+}
+
+/// Bitrate limits of a participant's transports in bps, 0 means no limit
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all="camelCase")]
+pub struct BitrateCaps {
+	/// What the participant can send, applied to its producer transport
+	pub max_incoming_bitrate: u32,
+	/// What the participant can receive, applied to its consumer transport
+	pub max_outgoing_bitrate: u32,
+}
+
+/// A request to change some bitrate caps, fields left to None are unchanged and 0 lifts a cap
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all="camelCase")]
+pub struct BitrateChange {
+	pub max_incoming_bitrate: Option<u32>,
+	pub max_outgoing_bitrate: Option<u32>,
+}
+
+/// Public identity of a participant, shared with the rest of the room
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all="camelCase")]
+pub struct ParticipantInfo {
+	pub id: ParticipantId,
+	pub display_name: Option<String>,
+	pub metadata: serde_json::Value,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all="camelCase")]
 pub struct TransportOptions {
@@ -30,8 +77,22 @@ pub struct TransportOptions {
 	dtls_parameters: DtlsParameters,
 	ice_parameters: IceParameters,
 	ice_candidates: Vec<IceCandidate>,
+	sctp_parameters: Option<SctpParameters>,
 }
 
+impl From<&WebRtcTransport> for TransportOptions {
+	fn from(transport: &WebRtcTransport) -> Self {
+		TransportOptions {
+			id: transport.id(),
+			dtls_parameters: transport.dtls_parameters(),
+			ice_candidates: transport.ice_candidates().clone(),
+			ice_parameters: transport.ice_parameters().clone(),
+			sctp_parameters: transport.sctp_parameters(),
+		}
+	}
+}
+
+#[derive(Clone)]
 struct Transports {
 	consumer: WebRtcTransport,
 	producer: WebRtcTransport,
@@ -39,12 +100,34 @@ struct Transports {
 
 struct Inner {
 	id: ParticipantId,
-	transports: Transports,
+	/// Replaced when the participant is migrated to another router
+	transports: Mutex<Transports>,
 	room: Room,
+	/// The router of the room this participant's transports live on
+	router: Mutex<Router>,
+	config: ParticipantConfig,
 	client_rtp_capabilities: Mutex<Option<RtpCapabilities>>,
 	consumers: Mutex<HashMap<ConsumerId, Consumer>>,
 	producers: Mutex<Vec<Producer>>,
-	attached_handlers: Mutex<Vec<HandlerId>>
+	data_consumers: Mutex<HashMap<DataConsumerId, DataConsumer>>,
+	data_producers: Mutex<Vec<DataProducer>>,
+	attached_handlers: Mutex<Vec<HandlerId>>,
+	resume_token: ResumeToken,
+	bitrate_caps: Mutex<BitrateCaps>,
+	/// Protocol features negotiated by the handshake of the current websocket
+	features: Mutex<Vec<String>>,
+	/// Room events outlive a single websocket, they are queued here while the participant is disconnected
+	events_tx: UnboundedSender<Message>,
+	events_rx: tokio::sync::Mutex<UnboundedReceiver<Message>>,
+}
+
+/// How a call to ParticipantConnection::run or resume ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEnd {
+	/// The participant left or the connection can't go on
+	Closed,
+	/// The websocket was lost, the session can be resumed
+	Dropped,
 }
 
 #[derive(Clone)]
@@ -53,98 +136,250 @@ pub struct ParticipantConnection {
 }
 
 impl ParticipantConnection {
-	pub async fn new(room: Room) -> Result<Self, Error> {
+	pub async fn new(room: Room, config: ParticipantConfig) -> Result<Self, Error> {
 
-		let router = room.router();
-		let server = room.webrtc_server();
+		let router_data = room.router_for_new_participant().await?;
 
-		let transport_opts = WebRtcTransportOptions::new_with_server(server.to_owned());
+		let bitrate_caps = config.max_bitrate;
+		let transports = create_transports(&router_data, bitrate_caps).await?;
 
-		let consumer = router.create_webrtc_transport(
-			transport_opts.clone()
-		)
-		.await
-		.map_err(|e| format!("Failed to create consumer transport: {e}"))?;
-
-		let producer = router.create_webrtc_transport(
-			transport_opts.clone()
-		)
-		.await
-		.map_err(|e| format!("Failed to create producer transport: {e}"))?;
+		let (events_tx, events_rx) = mpsc::unbounded_channel::<Message>();
 
 		Ok(ParticipantConnection {
 			inner: Arc::new(
 				Inner {
 					id: ParticipantId::new(),
-					transports: Transports {
-						consumer,
-						producer
-					},
+					transports: Mutex::new(transports),
 					room,
+					router: Mutex::new(router_data.router),
+					config,
 					client_rtp_capabilities: Mutex::new(None),
 					consumers: Mutex::new(HashMap::new()),
 					producers: Mutex::new(Vec::new()),
+					data_consumers: Mutex::new(HashMap::new()),
+					data_producers: Mutex::new(Vec::new()),
 					attached_handlers: Mutex::new(Vec::new()),
+					resume_token: ResumeToken::new(),
+					bitrate_caps: Mutex::new(bitrate_caps),
+					features: Mutex::new(Vec::new()),
+					events_tx,
+					events_rx: tokio::sync::Mutex::new(events_rx),
 				}
 			)
 		})
 	}
 
-	pub async fn run(&self, websocket: WebSocket) {
+	/// The room outlives its participants, its handlers must only hold a participant through this
+	pub fn downgrade(&self) -> WeakParticipantConnection {
+		WeakParticipantConnection { inner: Arc::downgrade(&self.inner) }
+	}
+
+	pub fn resume_token(&self) -> ResumeToken { self.inner.resume_token }
+
+	pub fn info(&self) -> ParticipantInfo {
+		ParticipantInfo {
+			id: self.inner.id,
+			display_name: self.inner.config.display_name.clone(),
+			metadata: self.inner.config.metadata.clone(),
+		}
+	}
+
+	/// Changes the bitrate caps of the participant's transports, never above the ones of its config.
+	/// The participant is told about its new caps with a ServerMessage::MaxBitrateChanged
+	pub async fn set_max_bitrate(&self, change: BitrateChange, request_id: Option<RequestId>) -> Result<BitrateCaps, RequestError> {
+		let max_bitrate = self.inner.config.max_bitrate;
+
+		// Each cap is recorded as soon as it's applied, so the stored caps match the transports even if the other one fails
+		if let Some(bitrate) = change.max_incoming_bitrate {
+			let bitrate = clamp_bitrate(bitrate, max_bitrate.max_incoming_bitrate);
+			self.transports().producer.set_max_incoming_bitrate(bitrate).await?;
+			self.inner.bitrate_caps.lock().max_incoming_bitrate = bitrate;
+		}
+		if let Some(bitrate) = change.max_outgoing_bitrate {
+			let bitrate = clamp_bitrate(bitrate, max_bitrate.max_outgoing_bitrate);
+			self.transports().consumer.set_max_outgoing_bitrate(bitrate).await?;
+			self.inner.bitrate_caps.lock().max_outgoing_bitrate = bitrate;
+		}
+
+		let caps = *self.inner.bitrate_caps.lock();
+		log::info!("Set bitrate caps of {:?} to {:?}", self.inner.id, caps);
+
+		let result = self.inner.events_tx.send(ServerMessage::MaxBitrateChanged {
+			request_id,
+			max_incoming_bitrate: caps.max_incoming_bitrate,
+			max_outgoing_bitrate: caps.max_outgoing_bitrate,
+		}.into());
+
+		if let Err(e) = result {
+			eprintln!("Failed to send message through the channel: {e}");
+		}
+
+		Ok(caps)
+	}
+
+	/// Moves the participant to the router that replaced its own after its worker died.
+	/// Its media died along with the old router, the client is sent a ServerMessage::Migrate
+	/// to connect the new transports and produce again, followed by the producers it can consume
+	pub async fn migrate(&self, router_data: RouterData) {
+		// A client that doesn't know about Migrate can only join again from scratch
+		if !self.has_feature("migration") {
+			log::info!("Closing participant {} whose router died, it doesn't support migrations", self.inner.id);
+			let _ = self.inner.events_tx.send(Internal::Close.into());
+			return;
+		}
+
+		let bitrate_caps = *self.inner.bitrate_caps.lock();
+		let transports = match create_transports(&router_data, bitrate_caps).await {
+			Ok(transports) => transports,
+			Err(e) => {
+				log::error!("Failed to migrate participant {} to router {}: {e}", self.inner.id, router_data.router.id());
+				let _ = self.inner.events_tx.send(Internal::Close.into());
+				return;
+			}
+		};
+
+		self.watch_ice_state(&transports, &self.inner.events_tx);
+		*self.inner.transports.lock() = transports.clone();
+		*self.inner.router.lock() = router_data.router.clone();
+
+		self.inner.consumers.lock().clear();
+		self.inner.data_consumers.lock().clear();
+
+		let producers = std::mem::take(&mut *self.inner.producers.lock());
+		for producer in producers {
+			self.inner.room.remove_producer(&self.inner.id, &producer.id());
+		}
+
+		let data_producers = std::mem::take(&mut *self.inner.data_producers.lock());
+		for data_producer in data_producers {
+			self.inner.room.remove_data_producer(&self.inner.id, &data_producer.id());
+		}
+
+		log::info!("Migrated participant {} to router {}", self.inner.id, router_data.router.id());
+
+		let result = self.inner.events_tx.send(ServerMessage::Migrate {
+			router_rtp_capabilities: router_data.router.rtp_capabilities().clone(),
+			consumer_transport_options: (&transports.consumer).into(),
+			producer_transport_options: (&transports.producer).into(),
+		}.into());
+
+		if let Err(e) = result {
+			eprintln!("Failed to send message through the channel: {e}");
+		}
+
+		// The client must consume again what it wants to receive
+		self.announce_producers(&self.inner.events_tx);
+
+		if self.inner.config.auto_subscribe {
+			for (participant, producer) in self.inner.room.get_all_producers() {
+				if participant.id == self.inner.id { continue; }
+				if let Err(e) = self.auto_consume(producer.id()).await {
+					eprintln!("Failed to send message through the channel: {e}");
+				}
+			}
+		}
+	}
+
+	pub async fn run(&self, websocket: WebSocket, encoding: Encoding, features: Vec<String>) -> ConnectionEnd {
+		*self.inner.features.lock() = features;
 		log::info!("New participant {} in room {}", self.inner.id, self.inner.room.id());
 		let _ = MonitorDispatch::send_event(SFUEvent::ParticipantEntered {
 			room_id: self.inner.room.id(),
 			participant_id: self.inner.id.clone(),
 		});
 
+		self.inner.room.add_participant(self.info(), self.router());
+
+		// Send a server ready message to the client
+		self.init_connection(self.inner.events_tx.clone());
+
+		self.serve(websocket, encoding).await
+	}
+
+	/// Reattaches a new websocket to this participant after its previous one was dropped.
+	/// Room events that happened in the meantime are delivered right after the Resumed message
+	pub async fn resume(&self, mut websocket: WebSocket, encoding: Encoding, features: Vec<String>) -> ConnectionEnd {
+		log::info!("Participant {} resumed its session in room {}", self.inner.id, self.inner.room.id());
+		*self.inner.features.lock() = features;
+
+		if let Err(e) = websocket.send(encoding.encode(&ServerMessage::Resumed)).await {
+			log::warn!("Failed to send the resume confirmation to participant {}: {e}", self.inner.id);
+			return ConnectionEnd::Dropped;
+		}
+
+		self.serve(websocket, encoding).await
+	}
+
+	async fn serve(&self, websocket: WebSocket, encoding: Encoding) -> ConnectionEnd {
 		let (ws_tx, ws_rx) = websocket.split();
+		// This channel only lives as long as the websocket
 		let (ch_tx, ch_rx) = mpsc::unbounded_channel::<Message>();
 
 		// The task that'll handle web socket messages
-		{
+		let receive_task = {
 			let ch_tx = ch_tx.clone();
 			let conn = self.clone();
 			tokio::spawn(async move {
 				if let Err(e) = conn.receive_ws_messages(ws_rx, ch_tx).await {
 					eprintln!("Error sending message through the channel: {e}");
 				}
-			});
-		}
-
-		// Send a server ready message to the client
-		{
-			let ch_tx = ch_tx.clone();
-			self.init_connection(ch_tx);
-		}
+			})
+		};
 
 		// This is what blocks the "run" function
 		// It receives various internal messages and handles them
-		self.handle_channel_endpoint(ws_tx, ch_rx).await;
+		let end = self.handle_channel_endpoint(ws_tx, ch_rx, encoding).await;
+
+		// The receiving task holds the participant alive, it must not outlive the connection
+		receive_task.abort();
+		end
+	}
+
+	/// Tells the client when it needs to restart ICE, e.g. after switching networks
+	/// The handlers are detached, they go away along with the transports
+	fn watch_ice_state(&self, transports: &Transports, ch_tx: &UnboundedSender<Message>) {
+		for transport in [&transports.consumer, &transports.producer] {
+			transport.on_ice_state_change({
+				let ch_tx = ch_tx.clone();
+				let transport_id = transport.id();
+
+				move |ice_state| {
+					if ice_state != IceState::Disconnected { return; }
+
+					let result = ch_tx.send(ServerMessage::TransportDisconnected { transport_id }.into());
+
+					if let Err(e) = result {
+						eprintln!("Failed to send message through the channel: {e}");
+					}
+				}
+			})
+			.detach();
+		}
 	}
 
 	/// Prepares the connection and sends a ServerMessage::Init when done
 	fn init_connection(&self, ch_tx: UnboundedSender<Message>) {
 		let (room_id, router, transports) = (
 			self.inner.room.id().clone(),
-			self.inner.room.router(),
-			&self.inner.transports
+			self.router(),
+			self.transports()
 		);
 
+		let participants = self.inner.room
+			.get_participants()
+			.into_iter()
+			.filter(|participant| participant.id != self.inner.id)
+			.collect();
+
 		let server_init = ServerMessage::Init {
 			room_id,
+			participant_id: self.inner.id,
+			participants,
 			router_rtp_capabilities: router.rtp_capabilities().clone(),
-			consumer_transport_options: TransportOptions {
-				id: transports.consumer.id().clone(),
-				dtls_parameters: transports.consumer.dtls_parameters().clone(),
-				ice_candidates: transports.consumer.ice_candidates().clone(),
-				ice_parameters: transports.consumer.ice_parameters().clone()
-			},
-			producer_transport_options: TransportOptions {
-				id: transports.producer.id().clone(),
-				dtls_parameters: transports.producer.dtls_parameters().clone(),
-				ice_candidates: transports.producer.ice_candidates().clone(),
-				ice_parameters: transports.producer.ice_parameters().clone()
-			},
+			media_policy: self.inner.room.media_policy().clone(),
+			consumer_transport_options: (&transports.consumer).into(),
+			producer_transport_options: (&transports.producer).into(),
+			resume_token: self.inner.resume_token,
 		};
 
 		if let Err(e) = ch_tx.send(server_init.into()) {
@@ -155,16 +390,53 @@ impl ParticipantConnection {
 		{
 			let mut attached_handlers = self.inner.attached_handlers.lock();
 
+			attached_handlers.push(room.on_participant_join({
+				let ch_tx = ch_tx.clone();
+				let own_id = self.inner.id;
+
+				move |participant| {
+					if participant.id == own_id { return; }
+
+					let result = ch_tx.send(ServerMessage::ParticipantJoined {
+						participant: participant.clone()
+					}.into());
+
+					if let Err(e) = result {
+						eprintln!("Failed to send message through the channel: {e}");
+					}
+				}
+			}));
+
+			attached_handlers.push(room.on_participant_leave({
+				let ch_tx = ch_tx.clone();
+				let own_id = self.inner.id;
+
+				move |participant_id| {
+					if *participant_id == own_id { return; }
+
+					let result = ch_tx.send(ServerMessage::ParticipantLeft {
+						participant_id: participant_id.to_owned()
+					}.into());
+
+					if let Err(e) = result {
+						eprintln!("Failed to send message through the channel: {e}");
+					}
+				}
+			}));
+
 			attached_handlers.push(room.on_producer_add({
 				let ch_tx = ch_tx.clone();
 				let own_id = self.inner.id.clone();
 
-				move |participant_id, producer| {
-					if *participant_id == own_id { return; }
+				move |participant, producer| {
+					if participant.id == own_id { return; }
 
 					let result = ch_tx.send(ServerMessage::ProducerAdded {
-						participant_id: participant_id.to_owned(),
-						producer_id: producer.id().clone()
+						participant_id: participant.id,
+						producer_id: producer.id().clone(),
+						display_name: participant.display_name.clone(),
+						metadata: participant.metadata.clone(),
+						paused: producer.paused(),
 					}.into());
 
 					if let Err(e) = result {
@@ -173,6 +445,25 @@ impl ParticipantConnection {
 				}
 			}));
 
+			if self.inner.config.auto_subscribe {
+				attached_handlers.push(room.on_producer_add({
+					let conn = self.downgrade();
+					let own_id = self.inner.id;
+
+					move |participant, producer| {
+						if participant.id == own_id { return; }
+						let Some(conn) = conn.upgrade() else { return; };
+
+						let producer_id = producer.id();
+						tokio::spawn(async move {
+							if let Err(e) = conn.auto_consume(producer_id).await {
+								eprintln!("Failed to send message through the channel: {e}");
+							}
+						});
+					}
+				}));
+			}
+
 			attached_handlers.push(room.on_producer_remove({
 				let ch_tx = ch_tx.clone();
 				let own_id = self.inner.id.clone();
@@ -191,6 +482,109 @@ impl ParticipantConnection {
 				}
 			}));
 
+			attached_handlers.push(room.on_dominant_speaker({
+				let ch_tx = ch_tx.clone();
+
+				move |participant_id, producer_id| {
+					let result = ch_tx.send(ServerMessage::DominantSpeaker {
+						participant_id: participant_id.to_owned(),
+						producer_id: producer_id.to_owned()
+					}.into());
+
+					if let Err(e) = result {
+						eprintln!("Failed to send message through the channel: {e}");
+					}
+				}
+			}));
+
+			attached_handlers.push(room.on_producer_pause({
+				let ch_tx = ch_tx.clone();
+				let own_id = self.inner.id;
+
+				move |participant_id, producer_id| {
+					if *participant_id == own_id { return; }
+
+					let result = ch_tx.send(ServerMessage::ProducerPaused {
+						participant_id: participant_id.to_owned(),
+						producer_id: producer_id.to_owned()
+					}.into());
+
+					if let Err(e) = result {
+						eprintln!("Failed to send message through the channel: {e}");
+					}
+				}
+			}));
+
+			attached_handlers.push(room.on_producer_resume({
+				let ch_tx = ch_tx.clone();
+				let own_id = self.inner.id;
+
+				move |participant_id, producer_id| {
+					if *participant_id == own_id { return; }
+
+					let result = ch_tx.send(ServerMessage::ProducerResumed {
+						participant_id: participant_id.to_owned(),
+						producer_id: producer_id.to_owned()
+					}.into());
+
+					if let Err(e) = result {
+						eprintln!("Failed to send message through the channel: {e}");
+					}
+				}
+			}));
+
+			attached_handlers.push(room.on_data_producer_add({
+				let ch_tx = ch_tx.clone();
+				let own_id = self.inner.id;
+
+				move |participant_id, data_producer| {
+					if *participant_id == own_id { return; }
+
+					let result = ch_tx.send(ServerMessage::DataProducerAdded {
+						participant_id: participant_id.to_owned(),
+						data_producer_id: data_producer.id()
+					}.into());
+
+					if let Err(e) = result {
+						eprintln!("Failed to send message through the channel: {e}");
+					}
+				}
+			}));
+
+			attached_handlers.push(room.on_data_producer_remove({
+				let ch_tx = ch_tx.clone();
+				let own_id = self.inner.id;
+
+				move |participant_id, data_producer_id| {
+					if *participant_id == own_id { return; }
+
+					let result = ch_tx.send(ServerMessage::DataProducerRemoved {
+						participant_id: participant_id.to_owned(),
+						data_producer_id: data_producer_id.to_owned()
+					}.into());
+
+					if let Err(e) = result {
+						eprintln!("Failed to send message through the channel: {e}");
+					}
+				}
+			}));
+
+			self.watch_ice_state(&transports, &ch_tx);
+
+			attached_handlers.push(room.on_router_migrate({
+				let conn = self.downgrade();
+
+				move |dead_router_id, router_data| {
+					let Some(conn) = conn.upgrade() else { return; };
+					if conn.router().id() != *dead_router_id { return; }
+
+					let router_data = router_data.clone();
+					tokio::spawn(async move {
+						conn.migrate(router_data).await;
+					});
+				}
+			}));
+
 			attached_handlers.push(room.on_fatal_error({
 				let ch_tx = ch_tx.clone();
 				move || {
@@ -199,10 +593,22 @@ impl ParticipantConnection {
 			}));
 		}
 
-		for (participant_id, producer_id) in room.get_all_producers() {
+		self.announce_producers(&ch_tx);
+	}
+
+	/// Sends a ProducerAdded and a DataProducerAdded for everything the rest of the room is producing
+	fn announce_producers(&self, ch_tx: &UnboundedSender<Message>) {
+		let room = &self.inner.room;
+
+		for (participant, producer) in room.get_all_producers() {
+			if participant.id == self.inner.id { continue; }
+
 			let result = ch_tx.send(ServerMessage::ProducerAdded {
-				participant_id,
-				producer_id,
+				participant_id: participant.id,
+				producer_id: producer.id(),
+				display_name: participant.display_name,
+				metadata: participant.metadata,
+				paused: producer.paused(),
 			}.into());
 
 			// This is getting repetitive ...
@@ -210,6 +616,19 @@ impl ParticipantConnection {
 				eprintln!("Failed to send message through the channel: {e}");
 			}
 		}
+
+		for (participant_id, data_producer_id) in room.get_all_data_producers() {
+			if participant_id == self.inner.id { continue; }
+
+			let result = ch_tx.send(ServerMessage::DataProducerAdded {
+				participant_id,
+				data_producer_id,
+			}.into());
+
+			if let Err(e) = result {
+				eprintln!("Failed to send message through the channel: {e}");
+			}
+		}
 	}
 
 	async fn receive_ws_messages(
@@ -232,8 +651,8 @@ impl ParticipantConnection {
 						continue;
 					} else {
 						eprintln!("websocket error: {e}. {ws_error_counter}/{MAX_ERRORS} errors. Closing connection");
-						ch_tx.send(Internal::Close.into())?;
-						break;
+						ch_tx.send(Internal::Disconnected.into())?;
+						return Ok(());
 					}
 				}
 			};
@@ -243,130 +662,507 @@ impl ParticipantConnection {
 					ch_tx.send(WsMessageKind::Pong(data).into())?;
 				},
 				WsMessageKind::Pong(_) => log::info!("Received pong from participant {}", self.inner.id),
-				WsMessageKind::Text(text) => match serde_json::from_str::<ClientMessage>(&text) {
-					Ok(client_msg) => self.handle_client_message(client_msg, ch_tx.clone()).await?,
-					Err(e) => eprintln!("Failed to parse JSON into a valid ClientMessage: {e}")
+				WsMessageKind::Text(text) => match serde_json::from_str::<ClientRequest>(&text) {
+					Ok(client_request) => self.handle_client_message(client_request, ch_tx.clone()).await?,
+					Err(e) => {
+						eprintln!("Failed to parse JSON into a valid ClientMessage: {e}");
+						ch_tx.send(ServerMessage::Error{
+							request_id: None,
+							code: ErrorCode::InvalidMessage,
+							message: format!("Failed to parse JSON into a valid ClientMessage: {e}")
+						}.into())?;
+					}
 				},
-				WsMessageKind::Binary(bin) => {
-					// Not a critical error, just warn the client
-					log::warn!("Received binary message: {:?}. The server does not handle binary messages", bin);
-					ch_tx.send(WsMessageKind::Text(
-						"Received binary data. Binary messages are not handled by the server. Please provide data in an expected JSON format".into())
-					.into())?;
+				WsMessageKind::Binary(bin) => match ClientRequest::from_message_pack(&bin) {
+					Ok(client_request) => self.handle_client_message(client_request, ch_tx.clone()).await?,
+					Err(e) => {
+						eprintln!("Failed to parse MessagePack into a valid ClientMessage: {e}");
+						ch_tx.send(ServerMessage::Error{
+							request_id: None,
+							code: ErrorCode::InvalidMessage,
+							message: format!("Failed to parse MessagePack into a valid ClientMessage: {e}")
+						}.into())?;
+					}
 				},
 				WsMessageKind::Close(_) => {
 					ch_tx.send(Internal::Close.into())?;
-					break;
+					return Ok(());
 				},
 			}
 		}
 
+		// The stream ended without a close frame, the client may come back
+		ch_tx.send(Internal::Disconnected.into())?;
 		Ok(())
 	}
 
-	async fn handle_client_message(&self, msg: ClientMessage, ch_tx: UnboundedSender<Message>) -> std::result::Result<(), SendError<Message>> {
-		match msg {
+	async fn handle_client_message(&self, request: ClientRequest, ch_tx: UnboundedSender<Message>) -> std::result::Result<(), SendError<Message>> {
+		let ClientRequest { request_id, message } = request;
+
+		match message {
 		    ClientMessage::Init { rtp_capabilities } => {
 				self.inner.client_rtp_capabilities.lock().replace(rtp_capabilities);
+
+				if self.inner.config.auto_subscribe {
+					for (participant, producer) in self.inner.room.get_all_producers() {
+						if participant.id == self.inner.id { continue; }
+						self.auto_consume(producer.id()).await?;
+					}
+				}
+
 				Ok(())
 			},
 		    ClientMessage::ConnectProducerTransport { dtls_parameters } => {
-				let producer_transport = self.inner.transports.producer.clone();
+				let producer_transport = self.transports().producer;
 
 				if let Err(e) = producer_transport.connect(WebRtcTransportRemoteParameters {
 					dtls_parameters
 				}).await {
 					eprintln!("Failed to connect producer transport for {:?}: {e}", self.inner.id);
+					return ch_tx.send(ServerMessage::Error{
+						request_id,
+						code: ErrorCode::TransportConnectFailed,
+						message: "Failed to connect the producer transport.".into()
+					}.into());
 				}
 
-				ch_tx.send(ServerMessage::ConnectedProducerTransport.into())
+				ch_tx.send(ServerMessage::ConnectedProducerTransport{request_id}.into())
 			},
 		    ClientMessage::ConnectConsumerTransport { dtls_parameters } => {
-				let consumer_transport = self.inner.transports.consumer.clone();
+				let consumer_transport = self.transports().consumer;
 
 				if let Err(e) = consumer_transport.connect(WebRtcTransportRemoteParameters { dtls_parameters }).await {
-					eprintln!("Failed to connect consumer transport for {:?}: {e}", self.inner.id)
+					eprintln!("Failed to connect consumer transport for {:?}: {e}", self.inner.id);
+					return ch_tx.send(ServerMessage::Error{
+						request_id,
+						code: ErrorCode::TransportConnectFailed,
+						message: "Failed to connect the consumer transport.".into()
+					}.into());
 				}
 
-				ch_tx.send(ServerMessage::ConnectedConsumerTransport.into())
+				ch_tx.send(ServerMessage::ConnectedConsumerTransport{request_id}.into())
 			},
-		    ClientMessage::Produce { kind, rtp_parameters } => {
-				let producer_transport = self.inner.transports.producer.clone();
+		    ClientMessage::Produce { kind, rtp_parameters, screen_share } => {
+				if let Err(reason) = self.inner.room.media_policy().check_produce(kind, &rtp_parameters, screen_share) {
+					log::info!("Refused {:?} producer of {:?}: {reason}", kind, self.inner.id);
+					return ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ProduceNotAllowed, message: reason}.into());
+				}
+
+				let producer_transport = self.transports().producer;
 				match producer_transport.produce(ProducerOptions::new(kind, rtp_parameters)).await {
 					Ok(producer) => {
 						log::info!("Created {:?} producer for {:?}", kind, self.inner.id);
-						self.inner.room.add_producer(self.inner.id.clone(), producer.clone());
-						ch_tx.send(ServerMessage::Produced{id: producer.id().clone()}.into())?;
+						// Handlers attached to a producer or consumer are detached, they go away along with it
+						producer.on_score({
+							let throttle = ScoreThrottle::new(self.inner.events_tx.clone());
+							let producer_id = producer.id();
+							move |scores| {
+								throttle.push(ServerMessage::ProducerScore {
+									id: producer_id,
+									scores: scores.to_vec()
+								});
+							}
+						})
+						.detach();
+
+						self.inner.room.add_producer(self.inner.id.clone(), producer.clone()).await;
+						ch_tx.send(ServerMessage::Produced{request_id, id: producer.id().clone()}.into())?;
 						self.inner.producers.lock().push(producer);
 						Ok(())
 					},
 					Err(e) => {
 						eprintln!("Failed to produce {:?} for {:?}: {e}", kind, self.inner.id);
-						ch_tx.send(ServerMessage::Warning{message: "Failed to produce, an unexpected error occured.".into()}.into())
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ProduceFailed, message: "Failed to produce, an unexpected error occured.".into()}.into())
 					}
 				}
 			},
 		    ClientMessage::Consume { producer_id } => {
-				let consumer_transport = self.inner.transports.consumer.clone();
-				let client_rtp_capabilities = self.inner.client_rtp_capabilities.lock().clone();
-
-				match client_rtp_capabilities {
-					Some(rtp_capabilities) => {
-						match consumer_transport.consume(ConsumerOptions::new(producer_id, rtp_capabilities)).await {
-							Ok(consumer) => {
-								log::info!("{producer_id} is now being consumed by participant {}", self.inner.id);
-								self.inner.consumers.lock().insert(consumer.id().clone(), consumer.clone());
-								ch_tx.send(ServerMessage::Consumed{
-									id: consumer.id().clone(),
-									kind: consumer.kind().clone(),
-									rtp_parameters: consumer.rtp_parameters().clone(),
-									producer_id: consumer.producer_id().clone(),
+				self.consume(producer_id, request_id, &ch_tx).await
+			},
+			ClientMessage::ConsumerResume {id} => {
+				match self.get_consumer(&id) {
+					Some(consumer) => {
+						if let Err(e) = consumer.resume().await {
+							eprintln!("Failed to resume consumer {id} for {:?}: {e}", self.inner.id);
+							return ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ConsumerResumeFailed, message: "Failed to resume the consumer, an unexpected error occured.".into()}.into());
+						}
+
+						log::info!("Resumed consumer {id} for {:?}", self.inner.id);
+
+						// Don't leave the client waiting for the next natural keyframe to render something
+						if consumer.kind() == MediaKind::Video {
+							if let Err(e) = consumer.request_key_frame().await {
+								eprintln!("Failed to request a keyframe for consumer {id} of {:?}: {e}", self.inner.id);
+							}
+						}
+
+						Ok(())
+					},
+					None => {
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ConsumerNotFound, message: "No consumer found for the provided id !".into()}.into())
+					}
+				}
+			},
+			ClientMessage::RequestKeyFrame { consumer_id } => {
+				match self.get_consumer(&consumer_id) {
+					Some(consumer) => {
+						if let Err(e) = consumer.request_key_frame().await {
+							eprintln!("Failed to request a keyframe for consumer {consumer_id} of {:?}: {e}", self.inner.id);
+							return ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::RequestKeyFrameFailed, message: "Failed to request a keyframe, an unexpected error occured.".into()}.into());
+						}
+
+						log::info!("Requested a keyframe for consumer {consumer_id} of {:?}", self.inner.id);
+						Ok(())
+					},
+					None => {
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ConsumerNotFound, message: "No consumer found for the provided id !".into()}.into())
+					}
+				}
+			},
+			ClientMessage::SetConsumerPreferredLayers { id, spatial_layer, temporal_layer } => {
+				match self.get_consumer(&id) {
+					Some(consumer) => {
+						let layers = ConsumerLayers { spatial_layer, temporal_layer };
+						if let Err(e) = consumer.set_preferred_layers(layers).await {
+							eprintln!("Failed to set preferred layers of consumer {id} for {:?}: {e}", self.inner.id);
+							return ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::SetPreferredLayersFailed, message: "Failed to set the preferred layers, an unexpected error occured.".into()}.into());
+						}
+
+						log::info!("Set preferred layers {:?} of consumer {id} for {:?}", layers, self.inner.id);
+						Ok(())
+					},
+					None => {
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ConsumerNotFound, message: "No consumer found for the provided id !".into()}.into())
+					}
+				}
+			},
+			ClientMessage::PauseProducer {id} => {
+				match self.get_producer(&id) {
+					Some(producer) => {
+						if let Err(e) = producer.pause().await {
+							eprintln!("Failed to pause producer {id} for {:?}: {e}", self.inner.id);
+							return ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::PauseProducerFailed, message: "Failed to pause the producer, an unexpected error occured.".into()}.into());
+						}
+
+						log::info!("Paused producer {id} for {:?}", self.inner.id);
+						self.inner.room.producer_paused(&self.inner.id, &id);
+						Ok(())
+					},
+					None => {
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ProducerNotFound, message: "No producer found for the provided id !".into()}.into())
+					}
+				}
+			},
+			ClientMessage::ResumeProducer {id} => {
+				match self.get_producer(&id) {
+					Some(producer) => {
+						if let Err(e) = producer.resume().await {
+							eprintln!("Failed to resume producer {id} for {:?}: {e}", self.inner.id);
+							return ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ResumeProducerFailed, message: "Failed to resume the producer, an unexpected error occured.".into()}.into());
+						}
+
+						log::info!("Resumed producer {id} for {:?}", self.inner.id);
+						self.inner.room.producer_resumed(&self.inner.id, &id);
+						Ok(())
+					},
+					None => {
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ProducerNotFound, message: "No producer found for the provided id !".into()}.into())
+					}
+				}
+			},
+			ClientMessage::CloseProducer {id} => {
+				let producer_maybe = {
+					let mut producers = self.inner.producers.lock();
+					producers
+						.iter()
+						.position(|producer| producer.id() == id)
+						.map(|index| producers.remove(index))
+				};
+
+				match producer_maybe {
+					Some(_) => {
+						// The producer is closed once the room drops its own reference
+						self.inner.room.remove_producer(&self.inner.id, &id);
+						log::info!("Closed producer {id} for {:?}", self.inner.id);
+						Ok(())
+					},
+					None => {
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ProducerNotFound, message: "No producer found for the provided id !".into()}.into())
+					}
+				}
+			},
+			ClientMessage::RestartIce { transport_id } => {
+				match self.get_transport(&transport_id) {
+					Some(transport) => {
+						match transport.restart_ice().await {
+							Ok(ice_parameters) => {
+								log::info!("Restarted ICE of transport {transport_id} for {:?}", self.inner.id);
+								ch_tx.send(ServerMessage::IceRestarted{
+									request_id,
+									transport_id,
+									ice_parameters
 								}.into())
 							},
 							Err(e) => {
-								eprintln!("Failed to consume {producer_id} for participant {:?}: {e}", self.inner.id);
-								ch_tx.send(ServerMessage::Warning{
-									message: "Failed to consume producer, an unexpected error occured.".into()
+								eprintln!("Failed to restart ICE of transport {transport_id} for {:?}: {e}", self.inner.id);
+								ch_tx.send(ServerMessage::Error{
+									request_id,
+									code: ErrorCode::IceRestartFailed,
+									message: "Failed to restart ICE, an unexpected error occured.".into()
 								}.into())
 							}
 						}
 					},
-					None =>{
-						ch_tx.send(ServerMessage::Warning{ message:
-							"You must send your RTP capabilities through an Init message before being able to consume".into()
+					None => {
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::TransportNotFound, message: "No transport found for the provided id !".into()}.into())
+					}
+				}
+			},
+			ClientMessage::ProduceData { sctp_stream_parameters, label, protocol } => {
+				let producer_transport = self.transports().producer;
+				let mut data_producer_options = DataProducerOptions::new_sctp(sctp_stream_parameters);
+				data_producer_options.label = label;
+				data_producer_options.protocol = protocol;
+
+				match producer_transport.produce_data(data_producer_options).await {
+					Ok(data_producer) => {
+						log::info!("Created data producer {} for {:?}", data_producer.id(), self.inner.id);
+						self.inner.room.add_data_producer(self.inner.id, data_producer.clone()).await;
+						ch_tx.send(ServerMessage::DataProduced{request_id, id: data_producer.id()}.into())?;
+						self.inner.data_producers.lock().push(data_producer);
+						Ok(())
+					},
+					Err(e) => {
+						eprintln!("Failed to produce data for {:?}: {e}", self.inner.id);
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ProduceDataFailed, message: "Failed to produce data, an unexpected error occured.".into()}.into())
+					}
+				}
+			},
+			ClientMessage::ConsumeData { data_producer_id } => {
+				let consumer_transport = self.transports().consumer;
+
+				match consumer_transport.consume_data(DataConsumerOptions::new_sctp(data_producer_id)).await {
+					Ok(data_consumer) => {
+						log::info!("{data_producer_id} is now being consumed by participant {}", self.inner.id);
+						self.inner.data_consumers.lock().insert(data_consumer.id(), data_consumer.clone());
+						ch_tx.send(ServerMessage::DataConsumed{
+							request_id,
+							id: data_consumer.id(),
+							data_producer_id: data_consumer.data_producer_id(),
+							sctp_stream_parameters: data_consumer.sctp_stream_parameters(),
+							label: data_consumer.label().clone(),
+							protocol: data_consumer.protocol().clone(),
+						}.into())
+					},
+					Err(e) => {
+						eprintln!("Failed to consume data producer {data_producer_id} for participant {:?}: {e}", self.inner.id);
+						ch_tx.send(ServerMessage::Error{
+							request_id,
+							code: ErrorCode::CannotConsumeData,
+							message: "Failed to consume data producer, an unexpected error occured.".into()
 						}.into())
 					}
 				}
 			},
-			ClientMessage::ConsumerResume {id} => {
-				let consumer_maybe = {
-					let consumers = self.inner.consumers.lock();
-					consumers.get(&id).map(|v| v.to_owned())
+			ClientMessage::SetMaxBitrate { participant_id, change } => {
+				match participant_id {
+					// There are no moderators yet, participants can only change their own caps, within the server-wide values
+					Some(participant_id) if participant_id != self.inner.id => {
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::NotAllowed, message: "Only your own bitrate caps can be changed.".into()}.into())
+					},
+					_ => match self.set_max_bitrate(change, request_id).await {
+						Ok(_) => Ok(()),
+						Err(e) => {
+							eprintln!("Failed to set the bitrate caps of {:?}: {e}", self.inner.id);
+							ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::SetMaxBitrateFailed, message: "Failed to set the bitrate caps, an unexpected error occured.".into()}.into())
+						}
+					}
+				}
+			},
+			ClientMessage::GetStats { target } => {
+				let stats = match target {
+					StatsTarget::Transport(id) => match self.get_transport(&id) {
+						Some(transport) => transport.get_stats().await.map(serde_json::to_value),
+						None => return ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::TransportNotFound, message: "No transport found for the provided id !".into()}.into()),
+					},
+					StatsTarget::Producer(id) => match self.get_producer(&id) {
+						Some(producer) => producer.get_stats().await.map(serde_json::to_value),
+						None => return ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ProducerNotFound, message: "No producer found for the provided id !".into()}.into()),
+					},
+					StatsTarget::Consumer(id) => match self.get_consumer(&id) {
+						Some(consumer) => consumer.get_stats().await.map(serde_json::to_value),
+						None => return ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::ConsumerNotFound, message: "No consumer found for the provided id !".into()}.into()),
+					},
 				};
 
-				match consumer_maybe {
-					Some(consumer) => {
-						if let Err(e) =  consumer.resume().await {
-							eprintln!("Failed to resume consumer {id} for {:?}: {e}", self.inner.id);
-						} else {
-							log::info!("Resumed consumer {id} for {:?}", self.inner.id);
-						}
+				match stats {
+					Ok(Ok(stats)) => ch_tx.send(ServerMessage::Stats{request_id, target, stats}.into()),
+					Ok(Err(e)) => {
+						eprintln!("Failed to serialize stats of {:?} for {:?}: {e}", target, self.inner.id);
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::StatsFailed, message: "Failed to get stats, an unexpected error occured.".into()}.into())
+					},
+					Err(e) => {
+						eprintln!("Failed to get stats of {:?} for {:?}: {e}", target, self.inner.id);
+						ch_tx.send(ServerMessage::Error{request_id, code: ErrorCode::StatsFailed, message: "Failed to get stats, an unexpected error occured.".into()}.into())
+					}
+				}
+			}
+		}
+	}
 
-						Ok(())
+	/// Creates a consumer for a producer of the room and sends a ServerMessage::Consumed when done
+	async fn consume(
+		&self,
+		producer_id: ProducerId,
+		request_id: Option<RequestId>,
+		ch_tx: &UnboundedSender<Message>
+	) -> std::result::Result<(), SendError<Message>> {
+		if self.inner.room.router_dispatch().capacity().headroom == 0 {
+			log::warn!("Refused to consume {producer_id} for participant {}, the server is at capacity", self.inner.id);
+			return ch_tx.send(ServerMessage::Error{
+				request_id,
+				code: ErrorCode::ServerAtCapacity,
+				message: "The server is at capacity, try again later.".into()
+			}.into());
+		}
+
+		let consumer_transport = self.transports().consumer;
+		let client_rtp_capabilities = self.inner.client_rtp_capabilities.lock().clone();
+
+		match client_rtp_capabilities {
+			Some(rtp_capabilities) => {
+				match consumer_transport.consume(ConsumerOptions::new(producer_id, rtp_capabilities)).await {
+					Ok(consumer) => {
+						log::info!("{producer_id} is now being consumed by participant {}", self.inner.id);
+						self.inner.consumers.lock().insert(consumer.id(), consumer.clone());
+
+						// Handlers attached to a producer or consumer are detached, they go away along with it
+						consumer.on_score({
+							let throttle = ScoreThrottle::new(self.inner.events_tx.clone());
+							let consumer_id = consumer.id();
+							move |score| {
+								throttle.push(ServerMessage::ConsumerScore {
+									id: consumer_id,
+									score: score.clone()
+								});
+							}
+						})
+						.detach();
+
+						// Let the client know which simulcast/SVC layers it is actually receiving
+						consumer.on_layers_change({
+							let ch_tx = self.inner.events_tx.clone();
+							let consumer_id = consumer.id();
+							move |layers| {
+								let result = ch_tx.send(ServerMessage::ConsumerLayersChanged {
+									id: consumer_id,
+									layers: *layers
+								}.into());
+
+								if let Err(e) = result {
+									eprintln!("Failed to send message through the channel: {e}");
+								}
+							}
+						})
+						.detach();
+
+						ch_tx.send(ServerMessage::Consumed{
+							request_id,
+							id: consumer.id(),
+							kind: consumer.kind(),
+							rtp_parameters: consumer.rtp_parameters().clone(),
+							producer_id: consumer.producer_id(),
+							producer_paused: consumer.producer_paused(),
+						}.into())
 					},
-					None => {
-						ch_tx.send(ServerMessage::Warning{message: "No consumer found for the provided id !".into()}.into())
+					Err(e) => {
+						eprintln!("Failed to consume {producer_id} for participant {:?}: {e}", self.inner.id);
+						ch_tx.send(ServerMessage::Error{
+							request_id,
+							code: ErrorCode::CannotConsume,
+							message: "Failed to consume producer, an unexpected error occured.".into()
+						}.into())
 					}
 				}
+			},
+			None =>{
+				ch_tx.send(ServerMessage::Error{ request_id, code: ErrorCode::NotInitialized, message:
+					"You must send your RTP capabilities through an Init message before being able to consume".into()
+				}.into())
 			}
 		}
 	}
 
-	async fn handle_channel_endpoint(&self, mut ws_tx: SplitSink<WebSocket, ws::Message>, mut ch_rx: UnboundedReceiver<Message>) {
+	/// Consumes a producer without the client asking for it, used in auto-subscribe mode
+	async fn auto_consume(&self, producer_id: ProducerId) -> std::result::Result<(), SendError<Message>> {
+		// Wait for the client's RTP capabilities, everything gets consumed once they arrive
+		if self.inner.client_rtp_capabilities.lock().is_none() {
+			return Ok(());
+		}
+
+		let already_consumed = self.inner.consumers
+			.lock()
+			.values()
+			.any(|consumer| consumer.producer_id() == producer_id);
+
+		if already_consumed {
+			return Ok(());
+		}
+
+		self.consume(producer_id, None, &self.inner.events_tx).await
+	}
+
+	/// Finds one of this participant's own transports
+	fn get_transport(&self, id: &TransportId) -> Option<WebRtcTransport> {
+		let transports = self.transports();
+		[transports.consumer, transports.producer]
+			.into_iter()
+			.find(|transport| transport.id() == *id)
+	}
+
+	fn transports(&self) -> Transports {
+		self.inner.transports.lock().clone()
+	}
+
+	fn router(&self) -> Router {
+		self.inner.router.lock().clone()
+	}
+
+	/// Finds one of this participant's own producers
+	fn get_producer(&self, id: &ProducerId) -> Option<Producer> {
+		self.inner.producers
+			.lock()
+			.iter()
+			.find(|producer| producer.id() == *id)
+			.cloned()
+	}
+
+	fn has_feature(&self, feature: &str) -> bool {
+		self.inner.features.lock().iter().any(|f| f == feature)
+	}
+
+	fn get_consumer(&self, id: &ConsumerId) -> Option<Consumer> {
+		self.inner.consumers.lock().get(id).cloned()
+	}
+
+	async fn handle_channel_endpoint(
+		&self,
+		mut ws_tx: SplitSink<WebSocket, ws::Message>,
+		mut ch_rx: UnboundedReceiver<Message>,
+		encoding: Encoding
+	) -> ConnectionEnd {
 		let mut send_error_counter = 0;
 		const MAX_ERRORS: u8 = 3;
 
-		while let Some(msg) = ch_rx.recv().await {
+		let mut events_rx = self.inner.events_rx.lock().await;
+
+		loop {
+			let msg = tokio::select! {
+				Some(msg) = ch_rx.recv() => msg,
+				Some(msg) = events_rx.recv() => msg,
+				else => return ConnectionEnd::Dropped,
+			};
+
 			let result = match msg {
 			    Message::WebSocket(ws_msg) => match ws_msg {
 			        WsMessageKind::Ping(bytes) => ws_tx.send(ws::Message::ping(bytes)).await,
@@ -376,14 +1172,15 @@ impl ParticipantConnection {
 				Message::Internal(int_msg) => match int_msg {
 					Internal::Close => {
 						// End connection
-						return; /* bye bye */
-					}
+						let _ = ws_tx.send(ws::Message::close()).await;
+						return ConnectionEnd::Closed; /* bye bye */
+					},
+					Internal::Disconnected => return ConnectionEnd::Dropped,
 				},
-				Message::Server(srv_msg) => {
-					match serde_json::to_string(&srv_msg) {
-						Ok(json_msg) => ws_tx.send(ws::Message::text(json_msg)).await,
-						Err(e) => panic!("A server message couldn't be converted to JSON. This should never happen. Error: {:?}", e)
-					}
+				Message::Server(srv_msg) => match srv_msg.required_feature() {
+					// The client didn't ask for these messages and may not understand them
+					Some(feature) if !self.has_feature(feature) => continue,
+					_ => ws_tx.send(encoding.encode(&srv_msg)).await
 				}
 			};
 
@@ -394,21 +1191,131 @@ impl ParticipantConnection {
 					continue;
 				} else {
 					log::error!("Error sending message: {e}\n{send_error_counter}/{MAX_ERRORS}; Closing connection");
-					break;
+					return ConnectionEnd::Dropped;
 				}
 			}
 		}
 	}
 }
 
-impl Drop for ParticipantConnection {
+/// Creates a participant's pair of transports on a router and applies its bitrate caps to them
+async fn create_transports(router_data: &RouterData, bitrate_caps: BitrateCaps) -> Result<Transports, Error> {
+	let mut transport_opts = WebRtcTransportOptions::new_with_server(router_data.webrtc_server.clone());
+	// Data channels are carried over SCTP
+	transport_opts.enable_sctp = true;
+
+	let consumer = router_data.router.create_webrtc_transport(
+		transport_opts.clone()
+	)
+	.await
+	.map_err(|e| format!("Failed to create consumer transport: {e}"))?;
+
+	let producer = router_data.router.create_webrtc_transport(
+		transport_opts.clone()
+	)
+	.await
+	.map_err(|e| format!("Failed to create producer transport: {e}"))?;
+
+	if bitrate_caps.max_incoming_bitrate > 0 {
+		producer.set_max_incoming_bitrate(bitrate_caps.max_incoming_bitrate)
+			.await
+			.map_err(|e| format!("Failed to set max incoming bitrate: {e}"))?;
+	}
+	if bitrate_caps.max_outgoing_bitrate > 0 {
+		consumer.set_max_outgoing_bitrate(bitrate_caps.max_outgoing_bitrate)
+			.await
+			.map_err(|e| format!("Failed to set max outgoing bitrate: {e}"))?;
+	}
+
+	Ok(Transports { consumer, producer })
+}
+
+/// Keeps a requested bitrate under the configured maximum, 0 meaning no limit for both
+fn clamp_bitrate(requested: u32, max: u32) -> u32 {
+	match (requested, max) {
+		(_, 0) => requested,
+		(0, _) => max,
+		_ => requested.min(max),
+	}
+}
+
+/// Sends at most one score message per SCORE_THROTTLE about a producer or consumer.
+/// The latest score of a window is sent when it ends, so the client never keeps a stale one
+struct ScoreThrottle {
+	ch_tx: UnboundedSender<Message>,
+	last_sent: Mutex<Option<Instant>>,
+	/// A flush is scheduled whenever this is Some
+	pending: Mutex<Option<ServerMessage>>,
+	/// Score handlers are called from mediasoup's threads, the delayed flush runs on the runtime
+	runtime: tokio::runtime::Handle,
+}
+
+impl ScoreThrottle {
+	fn new(ch_tx: UnboundedSender<Message>) -> Arc<Self> {
+		Arc::new(ScoreThrottle {
+			ch_tx,
+			last_sent: Mutex::new(None),
+			pending: Mutex::new(None),
+			runtime: tokio::runtime::Handle::current(),
+		})
+	}
+
+	fn push(self: &Arc<Self>, msg: ServerMessage) {
+		let mut last_sent = self.last_sent.lock();
+		let now = Instant::now();
+
+		match *last_sent {
+			Some(instant) if now.duration_since(instant) < SCORE_THROTTLE => {
+				if self.pending.lock().replace(msg).is_some() { return; }
+
+				let delay = SCORE_THROTTLE - now.duration_since(instant);
+				let throttle = self.clone();
+				self.runtime.spawn(async move {
+					tokio::time::sleep(delay).await;
+					throttle.flush();
+				});
+			},
+			_ => {
+				last_sent.replace(now);
+				drop(last_sent);
+				self.send(msg);
+			}
+		}
+	}
+
+	fn flush(&self) {
+		let mut last_sent = self.last_sent.lock();
+		let Some(msg) = self.pending.lock().take() else { return; };
+		last_sent.replace(Instant::now());
+		drop(last_sent);
+		self.send(msg);
+	}
+
+	fn send(&self, msg: ServerMessage) {
+		if let Err(e) = self.ch_tx.send(msg.into()) {
+			eprintln!("Failed to send message through the channel: {e}");
+		}
+	}
+}
+
+pub struct WeakParticipantConnection {
+	inner: Weak<Inner>
+}
+impl WeakParticipantConnection {
+	pub fn upgrade(&self) -> Option<ParticipantConnection> {
+		self.inner.upgrade().map(|inner| ParticipantConnection {inner})
+	}
+}
+
+// Only the last reference leaving tears the participant down, a suspended session keeps it alive
+impl Drop for Inner {
 	fn drop(&mut self) {
-		log::info!("Participant {} is leaving", self.inner.id);
-		self.inner.room.remove_participant(&self.inner.id);
+		log::info!("Participant {} is leaving", self.id);
+		self.room.remove_participant(&self.id);
 
 		let _ = MonitorDispatch::send_event(SFUEvent::ParticipantLeft {
-			room_id: self.inner.room.id(),
-			participant_id: self.inner.id
+			room_id: self.room.id(),
+			participant_id: self.id
 		});
 	}
 }
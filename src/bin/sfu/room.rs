@@ -1,30 +1,62 @@
 use confroom_server::monitoring::SFUEvent;
 use confroom_server::uuids::{RoomId, ParticipantId};
 use mediasoup::prelude::*;
+use mediasoup::rtp_parameters::{MimeType, RtpCodecCapabilityFinalized};
+use mediasoup::router::RouterId;
+use mediasoup::worker::WorkerId;
 use event_listener_primitives::{Bag, BagOnce, HandlerId};
 use parking_lot::Mutex;
 use std::collections::HashMap;
 use std::sync::{Arc, Weak};
 
+use crate::media_policy::MediaPolicy;
 use crate::monitor_dispatch::MonitorDispatch;
-use crate::router_dispatch::RouterData;
+use crate::participant::ParticipantInfo;
+use crate::router_dispatch::{RouterData, RouterDispatch};
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(Default)]
 struct Handlers {
-	producer_add: Bag<Arc<dyn Fn(&ParticipantId, &Producer) + Send + Sync + 'static>, ParticipantId, Producer>,
+	participant_join: Bag<Arc<dyn Fn(&ParticipantInfo) + Send + Sync + 'static>, ParticipantInfo>,
+	participant_leave: Bag<Arc<dyn Fn(&ParticipantId) + Send + Sync + 'static>, ParticipantId>,
+	producer_add: Bag<Arc<dyn Fn(&ParticipantInfo, &Producer) + Send + Sync + 'static>, ParticipantInfo, Producer>,
 	producer_remove: Bag<Arc<dyn Fn(&ParticipantId, &ProducerId) + Send + Sync + 'static>, ParticipantId, ProducerId>,
+	dominant_speaker: Bag<Arc<dyn Fn(&ParticipantId, &ProducerId) + Send + Sync + 'static>, ParticipantId, ProducerId>,
+	producer_pause: Bag<Arc<dyn Fn(&ParticipantId, &ProducerId) + Send + Sync + 'static>, ParticipantId, ProducerId>,
+	producer_resume: Bag<Arc<dyn Fn(&ParticipantId, &ProducerId) + Send + Sync + 'static>, ParticipantId, ProducerId>,
+	data_producer_add: Bag<Arc<dyn Fn(&ParticipantId, &DataProducer) + Send + Sync + 'static>, ParticipantId, DataProducer>,
+	data_producer_remove: Bag<Arc<dyn Fn(&ParticipantId, &DataProducerId) + Send + Sync + 'static>, ParticipantId, DataProducerId>,
+	router_migrate: Bag<Arc<dyn Fn(&RouterId, &RouterData) + Send + Sync + 'static>, RouterId, RouterData>,
 	close: BagOnce<Box<dyn FnOnce() + Send + 'static>>,
 	fatal_error: BagOnce<Box<dyn FnOnce() + Send + 'static>>
 }
 
+/// A participant as seen by the room
+#[derive(Debug)]
+struct RoomParticipant {
+	info: ParticipantInfo,
+	/// The router the participant's transports were created on
+	router: Router,
+	producers: Vec<Producer>,
+}
+
 // Room internal
 pub struct Inner {
 	id: RoomId,
-	router: Router,
-	webrtc_server: WebRtcServer,
-	clients: Mutex<HashMap<ParticipantId, Vec<Producer>>>,
+	router_dispatch: RouterDispatch,
+	/// Every router the room is spread over, each on a different worker. A new one is added
+	/// when the workers of the others are full, every producer of the room is piped to all of them.
+	/// Room-wide objects like the active speaker observer live on the first one
+	routers: tokio::sync::Mutex<Vec<RouterData>>,
+	/// Keeps alive the copies of producers piped from the router of their participant to the other ones
+	piped_producers: Mutex<HashMap<ProducerId, Vec<PipeProducerToRouterPair>>>,
+	piped_data_producers: Mutex<HashMap<DataProducerId, Vec<PipeDataProducerToRouterPair>>>,
+	media_policy: MediaPolicy,
+	/// Every audio producer of the room is observed to find out who is speaking
+	active_speaker_observer: Mutex<ActiveSpeakerObserver>,
+	clients: Mutex<HashMap<ParticipantId, RoomParticipant>>,
+	data_producers: Mutex<HashMap<ParticipantId, Vec<DataProducer>>>,
 	handlers: Handlers,
 	attached_handlers: Mutex<Vec<HandlerId>>,
 }
@@ -32,8 +64,8 @@ impl std::fmt::Display for Inner {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		f.debug_struct("Inner")
     		.field("id", &self.id)
-      		.field("router", &self.router)
         	.field("clients", &self.clients)
+        	.field("data_producers", &self.data_producers)
          	.field("handlers", &"...")
           	.finish()
 	}
@@ -52,78 +84,447 @@ pub struct Room {
 	inner: Arc<Inner>
 }
 impl Room {
-	pub async fn new(router_data: RouterData) -> Result<Self, Error> {
-		Self::new_with_id(router_data, RoomId::new()).await
-	}
+	/// Creates a room starting on router_data, router_dispatch is used to get more routers once its worker is full
+	pub async fn new_with_id(
+		router_data: RouterData,
+		router_dispatch: RouterDispatch,
+		id: RoomId,
+		media_policy: MediaPolicy) -> Result<Self, Error> {
+		// The router must have been created for this policy, or nobody could produce anything in the room
+		let codecs = &router_data.router.rtp_capabilities().codecs;
+		if codecs.is_empty() {
+			return Err("The media policy of the room doesn't allow any of the router codecs".into());
+		}
+		for codec in codecs {
+			let mime_type = match codec {
+				RtpCodecCapabilityFinalized::Audio { mime_type, .. } => MimeType::Audio(*mime_type),
+				RtpCodecCapabilityFinalized::Video { mime_type, .. } => MimeType::Video(*mime_type),
+				_ => continue,
+			};
+
+			if !media_policy.allows_codec(mime_type) {
+				return Err(format!("The router offers codec {mime_type:?} which the media policy of the room doesn't allow").into());
+			}
+		}
 
-	pub async fn new_with_id(router_data: RouterData, id: RoomId) -> Result<Self, Error> {
 		let _ = MonitorDispatch::send_event(SFUEvent::RoomOpened { id: id.clone() });
 		println!("Room {id} opened");
 
+		let active_speaker_observer = router_data.router
+			.create_active_speaker_observer(ActiveSpeakerObserverOptions::default())
+			.await
+			.map_err(|e| format!("Failed to create active speaker observer: {e}"))?;
+
 		let room = Room {
 			inner: Arc::new(Inner {
 				id,
-				router: router_data.router.clone(),
-				webrtc_server: router_data.webrtc_server.clone(),
+				router_dispatch,
+				routers: tokio::sync::Mutex::new(vec![router_data.clone()]),
+				piped_producers: Mutex::new(HashMap::new()),
+				piped_data_producers: Mutex::new(HashMap::new()),
+				media_policy,
+				active_speaker_observer: Mutex::new(active_speaker_observer.clone()),
 				clients: Mutex::new(HashMap::new()),
+				data_producers: Mutex::new(HashMap::new()),
 				handlers: Handlers::default(),
 				attached_handlers: Mutex::new(Vec::new()),
 		 })
 		};
 
-		// If the worker dies, transmit a message to active participants,
-		// this should cascade in the room closing itself
-		room.inner.attached_handlers.lock().push(router_data.on_worker_died_unexpectedly({
-			let room = room.clone();
-			move || {
-				room.inner.handlers.fatal_error.call_simple();
-			}
-		}));
+		room.watch_worker(&router_data);
+		room.watch_dominant_speaker(&active_speaker_observer);
 
 		Ok(room)
 	}
 
+	fn watch_dominant_speaker(&self, active_speaker_observer: &ActiveSpeakerObserver) {
+		self.inner.attached_handlers.lock().push(active_speaker_observer.on_dominant_speaker({
+			// The observer belongs to the room, a strong reference here would keep the room alive forever
+			let room = self.downgrade();
+			move |dominant_speaker| {
+				if let Some(room) = room.upgrade() {
+					room.dominant_speaker_changed(dominant_speaker.producer.id());
+				}
+			}
+		}));
+	}
+
 	pub fn downgrade(&self) -> WeakRoom {
 		WeakRoom { inner: Arc::downgrade(&self.inner) }
 	}
 
 	pub fn id(&self) -> RoomId { self.inner.id }
-	pub fn router(&self) -> &Router { &self.inner.router }
-	pub fn webrtc_server(&self) -> &WebRtcServer { &self.inner.webrtc_server }
-	pub fn add_producer(&self, participant_id: ParticipantId, producer: Producer) {
+	pub fn media_policy(&self) -> &MediaPolicy { &self.inner.media_policy }
+	pub fn router_dispatch(&self) -> &RouterDispatch { &self.inner.router_dispatch }
+
+	/// If the worker of one of the room's routers dies, the router is replaced and its participants migrated to the new one
+	fn watch_worker(&self, router_data: &RouterData) {
+		self.inner.attached_handlers.lock().push(router_data.on_worker_died_unexpectedly({
+			let room = self.downgrade();
+			let router_id = router_data.router.id();
+			move || {
+				let Some(room) = room.upgrade() else { return; };
+				tokio::spawn(async move {
+					room.replace_router(router_id).await;
+				});
+			}
+		}));
+	}
+
+	/// Replaces a router whose worker died. If that fails, a fatal error is sent to active participants,
+	/// this should cascade in the room closing itself
+	async fn replace_router(&self, dead_router_id: RouterId) {
+		let mut routers = self.inner.routers.lock().await;
+		let Some(index) = routers.iter().position(|r| r.router.id() == dead_router_id) else { return; };
+		routers.remove(index);
+
+		let used_workers: Vec<WorkerId> = routers.iter().map(|r| r.worker_id()).collect();
+		let router_data = match self.inner.router_dispatch.create_router(&self.inner.id, &self.inner.media_policy, &used_workers).await {
+			Ok(router_data) => router_data,
+			Err(e) => {
+				log::error!("Failed to replace dead router {dead_router_id} of room {}: {e}", self.inner.id);
+				self.inner.handlers.fatal_error.call_simple();
+				return;
+			}
+		};
+		log::warn!("Room {} replaced dead router {dead_router_id} by {} on worker {}",
+			self.inner.id, router_data.router.id(), router_data.worker_id());
+
+		// Their producers died along with the router, they'll produce again once migrated
+		for participant in self.inner.clients.lock().values_mut() {
+			if participant.router.id() == dead_router_id {
+				participant.router = router_data.router.clone();
+			}
+		}
+
+		self.pipe_everything_to(&router_data.router).await;
+
+		if index == 0 {
+			if let Err(e) = self.replace_active_speaker_observer(&router_data.router).await {
+				log::error!("Failed to replace the active speaker observer of room {}: {e}", self.inner.id);
+			}
+		}
+
+		self.watch_worker(&router_data);
+		routers.insert(index, router_data.clone());
+		drop(routers);
+
+		self.inner.handlers.router_migrate.call_simple(&dead_router_id, &router_data);
+	}
+
+	/// Recreates the active speaker observer on a new first router, observing the audio producers it has
+	async fn replace_active_speaker_observer(&self, router: &Router) -> Result<(), Error> {
+		let active_speaker_observer = router
+			.create_active_speaker_observer(ActiveSpeakerObserverOptions::default())
+			.await
+			.map_err(|e| format!("Failed to create active speaker observer: {e}"))?;
+
+		let audio_producers: Vec<ProducerId> = self.inner.clients
+			.lock()
+			.values()
+			.filter(|participant| participant.router.id() != router.id())
+			.flat_map(|participant| participant.producers.iter())
+			.filter(|producer| producer.kind() == MediaKind::Audio)
+			.map(|producer| producer.id())
+			.collect();
+
+		for producer_id in audio_producers {
+			active_speaker_observer.add_producer(RtpObserverAddProducerOptions::new(producer_id)).await
+				.map_err(|e| format!("Failed to observe audio producer {producer_id}: {e}"))?;
+		}
+
+		self.watch_dominant_speaker(&active_speaker_observer);
+		*self.inner.active_speaker_observer.lock() = active_speaker_observer;
+		Ok(())
+	}
+
+	/// Picks the router a new participant should create its transports on.
+	/// When the workers of every router of the room are full, the room is extended to a router on another worker
+	pub async fn router_for_new_participant(&self) -> Result<RouterData, Error> {
+		let mut routers = self.inner.routers.lock().await;
+
+		if let Some(router_data) = routers.iter().find(|r| self.inner.router_dispatch.has_capacity(&r.worker_id())) {
+			return Ok(router_data.clone());
+		}
+
+		// The room already is on every worker, a new router would only land next to one of its own
+		let used_workers: Vec<WorkerId> = routers.iter().map(|r| r.worker_id()).collect();
+		if let (false, Some(router_data)) = (self.inner.router_dispatch.has_worker_besides(&used_workers), routers.first()) {
+			return Ok(router_data.clone());
+		}
+
+		let router_data = self.inner.router_dispatch.create_router(&self.inner.id, &self.inner.media_policy, &used_workers).await
+			.map_err(|e| format!("Failed to create router: {e}"))?;
+
+		// An idle worker may have been released in the meantime, leaving the dispatch with only the room's ones
+		if let Some(existing) = routers.iter().find(|r| r.worker_id() == router_data.worker_id()) {
+			return Ok(existing.clone());
+		}
+
+		log::info!("Room {} extends to router {} on worker {}", self.inner.id, router_data.router.id(), router_data.worker_id());
+
+		self.pipe_everything_to(&router_data.router).await;
+
+		self.watch_worker(&router_data);
+		routers.push(router_data.clone());
+		Ok(router_data)
+	}
+
+	/// Pipes every producer and data producer of the room living on another router to a new router,
+	/// so that its participants see everything that's already being produced
+	async fn pipe_everything_to(&self, target: &Router) {
+		let producers: Vec<(Router, ProducerId)> = self.inner.clients
+			.lock()
+			.values()
+			.filter(|participant| participant.router.id() != target.id())
+			.flat_map(|participant| participant.producers.iter().map(|producer| (participant.router.clone(), producer.id())))
+			.collect();
+		for (source, producer_id) in producers {
+			self.pipe_producer(&source, producer_id, target).await;
+		}
+
+		let data_producers: Vec<(Router, DataProducerId)> = {
+			let clients = self.inner.clients.lock();
+			self.inner.data_producers
+				.lock()
+				.iter()
+				.filter_map(|(participant_id, data_producers)| {
+					clients.get(participant_id).map(|participant| (participant.router.clone(), data_producers))
+				})
+				.filter(|(router, _)| router.id() != target.id())
+				.flat_map(|(router, data_producers)| {
+					data_producers.iter().map(move |data_producer| (router.clone(), data_producer.id()))
+				})
+				.collect()
+		};
+		for (source, data_producer_id) in data_producers {
+			self.pipe_data_producer(&source, data_producer_id, target).await;
+		}
+	}
+
+	async fn pipe_producer(&self, source: &Router, producer_id: ProducerId, target: &Router) {
+		match source.pipe_producer_to_router(producer_id, PipeToRouterOptions::new(target.clone())).await {
+			Ok(pair) => {
+				self.inner.piped_producers.lock().entry(producer_id).or_default().push(pair);
+			},
+			Err(e) => log::error!("Failed to pipe producer {producer_id} to router {} in room {}: {e}", target.id(), self.inner.id),
+		}
+	}
+
+	async fn pipe_data_producer(&self, source: &Router, data_producer_id: DataProducerId, target: &Router) {
+		match source.pipe_data_producer_to_router(data_producer_id, PipeToRouterOptions::new(target.clone())).await {
+			Ok(pair) => {
+				self.inner.piped_data_producers.lock().entry(data_producer_id).or_default().push(pair);
+			},
+			Err(e) => log::error!("Failed to pipe data producer {data_producer_id} to router {} in room {}: {e}", target.id(), self.inner.id),
+		}
+	}
+
+	/// Routers of the room other than the one of that participant
+	async fn other_routers(&self, participant_router: &Router) -> Vec<Router> {
+		self.inner.routers
+			.lock()
+			.await
+			.iter()
+			.filter(|r| r.router.id() != participant_router.id())
+			.map(|r| r.router.clone())
+			.collect()
+	}
+
+	pub fn add_participant(&self, info: ParticipantInfo, router: Router) {
+		self.inner
+			.clients
+			.lock()
+			.insert(info.id, RoomParticipant { info: info.clone(), router, producers: Vec::new() });
+
+		self.inner.handlers.participant_join.call_simple(&info);
+	}
+
+	pub async fn add_producer(&self, participant_id: ParticipantId, producer: Producer) {
+		let (info, router) = {
+			let mut clients = self.inner.clients.lock();
+			match clients.get_mut(&participant_id) {
+				Some(participant) => {
+					participant.producers.push(producer.clone());
+					(participant.info.clone(), participant.router.clone())
+				},
+				None => {
+					log::error!("Participant {participant_id} produced but isn't part of room {}", self.inner.id);
+					return;
+				}
+			}
+		};
+
+		// Piped first so that participants on other routers can consume it as soon as they hear about it
+		for target in self.other_routers(&router).await {
+			self.pipe_producer(&router, producer.id(), &target).await;
+		}
+
+		self.inner.handlers.producer_add.call_simple(&info, &producer);
+
+		if producer.kind() == MediaKind::Audio {
+			let options = RtpObserverAddProducerOptions::new(producer.id());
+			let active_speaker_observer = self.inner.active_speaker_observer.lock().clone();
+			if let Err(e) = active_speaker_observer.add_producer(options).await {
+				log::error!("Failed to observe audio producer {} in room {}: {e}", producer.id(), self.inner.id);
+			}
+		}
+	}
+
+	fn dominant_speaker_changed(&self, producer_id: ProducerId) {
+		let participant_id = self.inner.clients
+			.lock()
+			.values()
+			.find(|participant| participant.producers.iter().any(|producer| producer.id() == producer_id))
+			.map(|participant| participant.info.id);
+
+		if let Some(participant_id) = participant_id {
+			let _ = MonitorDispatch::send_event(SFUEvent::DominantSpeaker {
+				room_id: self.inner.id,
+				participant_id,
+			});
+
+			self.inner.handlers.dominant_speaker.call_simple(&participant_id, &producer_id);
+		}
+	}
+
+	pub async fn add_data_producer(&self, participant_id: ParticipantId, data_producer: DataProducer) {
+		let router = match self.inner.clients.lock().get(&participant_id) {
+			Some(participant) => participant.router.clone(),
+			None => {
+				log::error!("Participant {participant_id} produced data but isn't part of room {}", self.inner.id);
+				return;
+			}
+		};
+
 		self.inner
-    		.clients
-      		.lock()
-        	.entry(participant_id)
-         	.or_default()
-          	.push(producer.clone());
+			.data_producers
+			.lock()
+			.entry(participant_id)
+			.or_default()
+			.push(data_producer.clone());
+
+		for target in self.other_routers(&router).await {
+			self.pipe_data_producer(&router, data_producer.id(), &target).await;
+		}
 
-		self.inner.handlers.producer_add.call_simple(&participant_id, &producer);
+		self.inner.handlers.data_producer_add.call_simple(&participant_id, &data_producer);
 	}
 
 	pub fn remove_participant(&self, participant_id: &ParticipantId) {
-		let producers = self.inner.clients.lock().remove(participant_id);
+		let participant = self.inner.clients.lock().remove(participant_id);
+		let was_present = participant.is_some();
+		let producers = participant.map(|participant| participant.producers);
 
 		for producer in producers.unwrap_or_default() {
 			let producer_id = &producer.id();
+			self.inner.piped_producers.lock().remove(producer_id);
+			self.inner.handlers.producer_remove.call_simple(participant_id, producer_id);
+		}
+
+		let data_producers = self.inner.data_producers.lock().remove(participant_id);
+
+		for data_producer in data_producers.unwrap_or_default() {
+			let data_producer_id = &data_producer.id();
+			self.inner.piped_data_producers.lock().remove(data_producer_id);
+			self.inner.handlers.data_producer_remove.call_simple(participant_id, data_producer_id);
+		}
+
+		if was_present {
+			self.inner.handlers.participant_leave.call_simple(participant_id);
+		}
+	}
+
+	/// Removes a single producer from a participant, the participant stays in the room
+	pub fn remove_producer(&self, participant_id: &ParticipantId, producer_id: &ProducerId) {
+		let removed = {
+			let mut clients = self.inner.clients.lock();
+			match clients.get_mut(participant_id) {
+				Some(participant) => {
+					let producers = &mut participant.producers;
+					let len_before = producers.len();
+					producers.retain(|producer| producer.id() != *producer_id);
+					producers.len() != len_before
+				},
+				None => false
+			}
+		};
+
+		if removed {
+			self.inner.piped_producers.lock().remove(producer_id);
 			self.inner.handlers.producer_remove.call_simple(participant_id, producer_id);
 		}
 	}
 
-	pub fn get_all_producers(&self) -> Vec<(ParticipantId, ProducerId)> {
+	/// Removes a single data producer from a participant, the participant stays in the room
+	pub fn remove_data_producer(&self, participant_id: &ParticipantId, data_producer_id: &DataProducerId) {
+		let removed = match self.inner.data_producers.lock().get_mut(participant_id) {
+			Some(data_producers) => {
+				let len_before = data_producers.len();
+				data_producers.retain(|data_producer| data_producer.id() != *data_producer_id);
+				data_producers.len() != len_before
+			},
+			None => false
+		};
+
+		if removed {
+			self.inner.piped_data_producers.lock().remove(data_producer_id);
+			self.inner.handlers.data_producer_remove.call_simple(participant_id, data_producer_id);
+		}
+	}
+
+	/// Notifies the room that a participant paused one of its producers
+	pub fn producer_paused(&self, participant_id: &ParticipantId, producer_id: &ProducerId) {
+		self.inner.handlers.producer_pause.call_simple(participant_id, producer_id);
+	}
+
+	/// Notifies the room that a participant resumed one of its producers
+	pub fn producer_resumed(&self, participant_id: &ParticipantId, producer_id: &ProducerId) {
+		self.inner.handlers.producer_resume.call_simple(participant_id, producer_id);
+	}
+
+	pub fn get_participants(&self) -> Vec<ParticipantInfo> {
+		self.inner.clients
+			.lock()
+			.values()
+			.map(|participant| participant.info.clone())
+			.collect()
+	}
+
+	pub fn get_all_producers(&self) -> Vec<(ParticipantInfo, Producer)> {
 		self.inner.clients
 					.lock()
-    				.iter()
-        			.flat_map(|(participant_id, producers)| {
-           				let participant_id = *participant_id;
-						producers
+    				.values()
+        			.flat_map(|participant| {
+						participant.producers
 							.iter()
-							.map(move |producer| (participant_id, producer.id()))
+							.map(|producer| (participant.info.clone(), producer.clone()))
            			})
               		.collect()
 	}
 
-	pub fn on_producer_add<F: Fn(&ParticipantId, &Producer) + Send + Sync + 'static>(&self, callback: F) -> HandlerId {
+	pub fn get_all_data_producers(&self) -> Vec<(ParticipantId, DataProducerId)> {
+		self.inner.data_producers
+			.lock()
+			.iter()
+			.flat_map(|(participant_id, data_producers)| {
+				let participant_id = *participant_id;
+				data_producers
+					.iter()
+					.map(move |data_producer| (participant_id, data_producer.id()))
+			})
+			.collect()
+	}
+
+	pub fn on_participant_join<F: Fn(&ParticipantInfo) + Send + Sync + 'static>(&self, callback: F) -> HandlerId {
+		self.inner.handlers.participant_join.add(Arc::new(callback))
+	}
+
+	pub fn on_participant_leave<F: Fn(&ParticipantId) + Send + Sync + 'static>(&self, callback: F) -> HandlerId {
+		self.inner.handlers.participant_leave.add(Arc::new(callback))
+	}
+
+	pub fn on_producer_add<F: Fn(&ParticipantInfo, &Producer) + Send + Sync + 'static>(&self, callback: F) -> HandlerId {
 		self.inner.handlers.producer_add.add(Arc::new(callback))
 	}
 
@@ -131,6 +532,26 @@ impl Room {
 		self.inner.handlers.producer_remove.add(Arc::new(callback))
 	}
 
+	pub fn on_dominant_speaker<F: Fn(&ParticipantId, &ProducerId) + Send + Sync + 'static>(&self, callback: F) -> HandlerId {
+		self.inner.handlers.dominant_speaker.add(Arc::new(callback))
+	}
+
+	pub fn on_producer_pause<F: Fn(&ParticipantId, &ProducerId) + Send + Sync + 'static>(&self, callback: F) -> HandlerId {
+		self.inner.handlers.producer_pause.add(Arc::new(callback))
+	}
+
+	pub fn on_producer_resume<F: Fn(&ParticipantId, &ProducerId) + Send + Sync + 'static>(&self, callback: F) -> HandlerId {
+		self.inner.handlers.producer_resume.add(Arc::new(callback))
+	}
+
+	pub fn on_data_producer_add<F: Fn(&ParticipantId, &DataProducer) + Send + Sync + 'static>(&self, callback: F) -> HandlerId {
+		self.inner.handlers.data_producer_add.add(Arc::new(callback))
+	}
+
+	pub fn on_data_producer_remove<F: Fn(&ParticipantId, &DataProducerId) + Send + Sync + 'static>(&self, callback: F) -> HandlerId {
+		self.inner.handlers.data_producer_remove.add(Arc::new(callback))
+	}
+
 	pub fn on_close<F: FnOnce() + Send + 'static>(&self, callback: F) -> HandlerId {
 		let _ = MonitorDispatch::send_event(SFUEvent::RoomClosed {
 			id: self.id()
@@ -139,6 +560,12 @@ impl Room {
 		self.inner.handlers.close.add(Box::new(callback))
 	}
 
+	/// Called with the id of a router whose worker died and the router replacing it,
+	/// participants that were on the dead router must move to the new one
+	pub fn on_router_migrate<F: Fn(&RouterId, &RouterData) + Send + Sync + 'static>(&self, callback: F) -> HandlerId {
+		self.inner.handlers.router_migrate.add(Arc::new(callback))
+	}
+
 	pub fn on_fatal_error<F: FnOnce() + Send + 'static>(&self, callback: F) -> HandlerId {
 		self.inner.handlers.fatal_error.add(Box::new(callback))
 	}
@@ -2,34 +2,76 @@ use parking_lot::Mutex;
 use std::{collections::hash_map::Entry, sync::Arc};
 use std::collections::HashMap;
 use confroom_server::uuids::RoomId;
+use crate::media_policy::MediaPolicy;
 use crate::room::{WeakRoom, Room};
-use crate::router_dispatch::RouterData;
+use crate::router_dispatch::RouterDispatch;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(Debug, Default, Clone)]
 pub struct RoomsRegistry {
-	rooms: Arc<Mutex<HashMap<RoomId, WeakRoom>>>
+	rooms: Arc<Mutex<HashMap<RoomId, WeakRoom>>>,
+	/// Held per room id while that room is being created, so two participants joining at once end up in the same room
+	/// without making the creation of other rooms wait
+	creating: Arc<Mutex<HashMap<RoomId, Arc<tokio::sync::Mutex<()>>>>>,
 }
 impl RoomsRegistry {
 	pub fn new() -> Self {
-		RoomsRegistry { rooms: Arc::new(Mutex::new(HashMap::new())) }
+		RoomsRegistry {
+			rooms: Arc::new(Mutex::new(HashMap::new())),
+			creating: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	fn get(&self, room_id: &RoomId) -> Option<Room> {
+		match self.rooms.lock().entry(*room_id) {
+			Entry::Occupied(entry) => entry.get().upgrade(),
+			Entry::Vacant(_) => None,
+		}
 	}
 
+	/// Returns the room with that id, a router is only requested from the dispatch if the room has to be created
 	pub async fn get_or_create(
 		&self,
 		room_id: RoomId,
-		router_data: RouterData) -> Result<Room, Error> {
+		router_dispatch: &RouterDispatch,
+		media_policy: MediaPolicy) -> Result<Room, Error> {
 
-		// First lock the rooms and check if a room exists with that ID
-		if let Entry::Occupied(entry) = self.rooms.lock().entry(room_id.clone()) {
-			if let Some(room) = entry.get().upgrade() {
-				return Ok(room)
-			}
+		// First check if a room exists with that ID
+		if let Some(room) = self.get(&room_id) {
+			return Ok(room);
+		}
+
+		let creation = self.creating.lock().entry(room_id).or_default().clone();
+		let result = {
+			let _creation = creation.lock().await;
+			self.create_if_missing(room_id, router_dispatch, media_policy).await
+		};
+
+		// Participants still waiting for their turn need the same lock
+		let mut creating = self.creating.lock();
+		if Arc::strong_count(&creation) <= 2 {
+			creating.remove(&room_id);
+		}
+
+		result
+	}
+
+	async fn create_if_missing(
+		&self,
+		room_id: RoomId,
+		router_dispatch: &RouterDispatch,
+		media_policy: MediaPolicy) -> Result<Room, Error> {
+
+		// It might have been created while we were waiting for our turn
+		if let Some(room) = self.get(&room_id) {
+			return Ok(room);
 		}
 
 		// No room exists, create a new one
-		let room = Room::new_with_id(router_data, room_id).await?;
+		let router_data = router_dispatch.create_router(&room_id, &media_policy, &[]).await
+			.map_err(|e| format!("Failed to create router: {e}"))?;
+		let room = Room::new_with_id(router_data, router_dispatch.clone(), room_id, media_policy).await?;
 
 		let mut rooms = self.rooms.lock();
 		rooms.insert(room_id, room.downgrade());
@@ -47,8 +89,11 @@ impl RoomsRegistry {
 		Ok(room)
 	}
 
-	pub async fn create_room(&self, router_data: RouterData) -> Result<Room, Error> {
-		let room = Room::new(router_data).await?;
+	pub async fn create_room(&self, router_dispatch: &RouterDispatch, media_policy: MediaPolicy) -> Result<Room, Error> {
+		let room_id = RoomId::new();
+		let router_data = router_dispatch.create_router(&room_id, &media_policy, &[]).await
+			.map_err(|e| format!("Failed to create router: {e}"))?;
+		let room = Room::new_with_id(router_data, router_dispatch.clone(), room_id, media_policy).await?;
 
 		self.rooms
 				.lock()
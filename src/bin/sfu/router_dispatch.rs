@@ -1,42 +1,35 @@
 mod worker_data;
 mod router_dispatch_config;
 mod router_data;
+mod codecs;
+mod worker_selection;
 
-use std::num::{NonZeroU32, NonZeroU8};
 use std::sync::Arc;
 use event_listener_primitives::HandlerId;
-use mediasoup::{prelude::*, worker::{CreateRouterError, WorkerId, WorkerLogTag}};
+use mediasoup::{prelude::*, rtp_parameters::MimeType, worker::{CreateRouterError, WorkerId, WorkerLogTag}};
 use parking_lot::Mutex;
+use serde::Serialize;
 use worker_data::WorkerData;
+use worker_selection::{WorkerLoad, WorkerSelector};
+use confroom_server::uuids::RoomId;
+use crate::media_policy::MediaPolicy;
 
 pub use worker_data::ANNOUNCED_ADDRESS_ENV_KEY;
 pub use router_dispatch_config::RouterDispatchConfig;
 pub use router_data::RouterData;
+pub use codecs::{CodecPreset, load_codecs_file};
+pub use worker_selection::WorkerSelection;
 
-fn supported_codecs() -> Vec<RtpCodecCapability> {
-	vec![
-	   RtpCodecCapability::Audio {
-	            mime_type: MimeTypeAudio::Opus,
-	            preferred_payload_type: None,
-	            clock_rate: NonZeroU32::new(48000).unwrap(),
-	            channels: NonZeroU8::new(2).unwrap(),
-	            parameters: RtpCodecParametersParameters::from([("useinbandfec", 1_u32.into())]),
-	            rtcp_feedback: vec![RtcpFeedback::TransportCc],
-		},
-		RtpCodecCapability::Video {
-			mime_type: MimeTypeVideo::Vp8,
-			preferred_payload_type: None,
-			clock_rate: NonZeroU32::new(90000).unwrap(),
-			parameters: RtpCodecParametersParameters::default(),
-			rtcp_feedback: vec![
-                RtcpFeedback::Nack,
-                RtcpFeedback::NackPli,
-                RtcpFeedback::CcmFir,
-                RtcpFeedback::GoogRemb,
-                RtcpFeedback::TransportCc,
-            ]
-		},
-	]
+/// How many more consumers the dispatch can take before every worker reached consumers_per_worker
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all="camelCase")]
+pub struct Capacity {
+	pub workers: usize,
+	pub max_workers: usize,
+	pub consumers: u32,
+	pub max_consumers: u32,
+	/// Consumers left before every worker, running or not yet created, is full
+	pub headroom: u32,
 }
 
 #[derive(Clone)]
@@ -45,8 +38,12 @@ pub struct RouterDispatch {
 	worker_manager: WorkerManager,
 	workers: Arc<Mutex<Vec<WorkerData>>>,
 	max_workers: usize,
-	/// this should be used to determine if we've reached the limit of this worker
+	/// Past this many consumers, a worker shouldn't be given new participants
 	consumers_per_worker: u32,
+	/// Media codecs enabled on every router
+	codecs: Arc<Vec<RtpCodecCapability>>,
+	worker_selector: Arc<dyn WorkerSelector>,
+	worker_creation: Arc<tokio::sync::Mutex<()>>,
 }
 
 impl Default for RouterDispatch {
@@ -62,27 +59,49 @@ impl RouterDispatch {
 			workers: Arc::new(Mutex::new(Vec::with_capacity(config.max_workers))),
 			max_workers: config.max_workers,
 			consumers_per_worker: config.consumers_per_worker,
+			codecs: Arc::new(config.codecs),
+			worker_selector: config.worker_selection.selector(),
+			worker_creation: Arc::new(tokio::sync::Mutex::new(())),
 		}
 	}
 
-	/// Creates a new router for use in a room. That router can be dropped if uneeded.
-	/// Note that this might cause the associated worker to die as well
-	pub async fn create_router(&self) -> Result<RouterData, CreateRouterError> {
-		let worker = self.get_or_create_appropriate_worker().await;
-		let router = worker.create_router(RouterOptions::new(supported_codecs())).await?;
+	/// Creates a new router for use in a room, only offering the codecs allowed by its media policy.
+	/// Workers in excluded_workers are avoided unless there is no other one.
+	/// That router can be dropped if uneeded. Note that this might cause the associated worker to die as well
+	pub async fn create_router(
+		&self,
+		room_id: &RoomId,
+		media_policy: &MediaPolicy,
+		excluded_workers: &[WorkerId]) -> Result<RouterData, CreateRouterError> {
+		let codecs = self.codecs
+			.iter()
+			.filter(|codec| {
+				let mime_type = match codec {
+					RtpCodecCapability::Audio { mime_type, .. } => MimeType::Audio(*mime_type),
+					RtpCodecCapability::Video { mime_type, .. } => MimeType::Video(*mime_type),
+				};
+				media_policy.allows_codec(mime_type)
+			})
+			.cloned()
+			.collect();
+
+		let worker = self.get_or_create_appropriate_worker(room_id, excluded_workers).await;
+		let router = worker.create_router(RouterOptions::new(codecs)).await?;
 
-		let handler = router.on_close({
+		// Handlers attached to the router are detached, they go away along with it
+		router.on_close({
 			let worker_id = worker.id();
 			let other_self = self.clone();
 			move || {
 				other_self.on_router_closed(worker_id);
 			}
-		});
-		push_handler(&self.workers, &worker.id(), handler);
+		})
+		.detach();
 
 		let (webrtc_server, worker_died_unexpectedly) = {
-			let workers = self.workers.lock();
-			let worker_data = workers.iter().find(|w| w.worker.id() == worker.id()).unwrap();
+			let mut workers = self.workers.lock();
+			let worker_data = workers.iter_mut().find(|w| w.worker.id() == worker.id()).unwrap();
+			worker_data.router_count += 1;
 			(worker_data.webrtc_server.clone(), worker_data.worker_died_unexpectedly.clone())
 		};
 
@@ -93,114 +112,211 @@ impl RouterDispatch {
 		})
 	}
 
+	pub fn capacity(&self) -> Capacity {
+		let workers = self.workers.lock();
+		let consumers = workers.iter().map(|w| w.get_num_consumers()).sum();
+		let not_created = self.max_workers.saturating_sub(workers.len()) as u32;
+		let headroom = workers
+			.iter()
+			.map(|w| self.consumers_per_worker.saturating_sub(w.get_num_consumers()))
+			.sum::<u32>() + not_created * self.consumers_per_worker;
+
+		Capacity {
+			workers: workers.len(),
+			max_workers: self.max_workers,
+			consumers,
+			max_consumers: self.max_workers as u32 * self.consumers_per_worker,
+			headroom,
+		}
+	}
+
+	/// Whether a worker can still take new participants
+	pub fn has_capacity(&self, worker_id: &WorkerId) -> bool {
+		self.workers
+			.lock()
+			.iter()
+			.find(|w| w.worker.id() == *worker_id)
+			.is_some_and(|w| w.consumer_count < self.consumers_per_worker)
+	}
+
+	/// Whether a router could be created on a worker that isn't excluded, either an existing one or a new one
+	pub fn has_worker_besides(&self, excluded_workers: &[WorkerId]) -> bool {
+		let workers = self.workers.lock();
+		workers.len() < self.max_workers || workers.iter().any(|w| !excluded_workers.contains(&w.worker.id()))
+	}
+
 	fn count_consumers_on_router(&self, router: Router) -> Router {
 		let worker = router.worker();
 		// ⚠️this is an ugly function
-		let new_transport_handler = router.on_new_transport({
+		// Every handler here belongs to the object it's attached to, so they are detached instead of
+		// being stored on the worker where they would pile up for as long as it lives
+		router.on_new_transport({
 			let workers_ref = self.workers.clone();
 			let associated_worker_id = worker.id();
 			move |new_transport| {
-				let new_consumer_handler = new_transport.on_new_consumer(Arc::new({
+				new_transport.on_new_consumer(Arc::new({
 					let workers_ref = workers_ref.clone();
 					let associated_worker_id = associated_worker_id.clone();
 					move |consumer| {
 						increase_consumer_count(&workers_ref, &associated_worker_id);
 
-						let consumer_close_handler = consumer.on_close({
+						consumer.on_close({
 							let workers_ref = workers_ref.clone();
 							let associated_worker_id = associated_worker_id.clone();
 							move || {
 								decrease_consumer_count(&workers_ref, &associated_worker_id);
-						}});
-						push_handler(&workers_ref, &associated_worker_id, consumer_close_handler);
+						}})
+						.detach();
 					}
-				}));
-				push_handler(&workers_ref, &associated_worker_id, new_consumer_handler);
+				}))
+				.detach();
 			}
-		});
-		push_handler(&self.workers, &worker.id(), new_transport_handler);
+		})
+		.detach();
 		router
 	}
 
 	/// Gets a worker ready to accept new routers or creates one if conditions permit it
 	/// This function can panic if no worker is stored and no worker can be created
-	async fn get_or_create_appropriate_worker(&self) -> Worker {
+	async fn get_or_create_appropriate_worker(&self, room_id: &RoomId, excluded_workers: &[WorkerId]) -> Worker {
 		// Create new workers while the vec is not filled
-		if self.workers.lock().len() < self.max_workers {
-			let worker_maybe = self.worker_manager.create_worker({
-				let mut settings = WorkerSettings::default();
-				settings.log_tags = vec![
-					WorkerLogTag::Info,
-					WorkerLogTag::Ice,
-					WorkerLogTag::Dtls,
-					WorkerLogTag::Rtp,
-					WorkerLogTag::Rtcp,
-					WorkerLogTag::Srtp,
-					WorkerLogTag::Rtx,
-					WorkerLogTag::Bwe,
-					WorkerLogTag::Score,
-					WorkerLogTag::Simulcast,
-					WorkerLogTag::Svc,
-					WorkerLogTag::Sctp,
-					WorkerLogTag::Message
-				];
-				settings
-			}).await;
-
-			match worker_maybe {
-				Ok(worker) => {
-					log::info!("Created new worker {}", worker.id());
-
-					// Create the structure that'll hold the worker alive
-					match WorkerData::new(worker.clone()).await {
-						Ok(worker_data) => {
-							self.workers.lock().push(worker_data);
-							log::debug!("There are {}/{} workers currently", self.workers.lock().len(), self.max_workers);
-
-							let handler = worker.on_dead({
-								let other_self = self.clone();
-								let worker_id = worker.id();
-								move |r| {
-									log::warn!("Worker died for reason {}", r.err().unwrap());
-									other_self.on_worker_dead(worker_id);
-								}
-							});
-							push_handler(&self.workers, &worker.id(), handler);
-
-							return worker;
-						},
-						Err(e) => {
-							log::error!("Failed to create Worker data: {e}");
-						}
+		if let Some(worker) = self.create_worker_if_allowed().await {
+			return worker;
+		}
+
+		self.select_worker(room_id, excluded_workers)
+	}
+
+	/// Creates a worker if there are less than max_workers
+	async fn create_worker_if_allowed(&self) -> Option<Worker> {
+		// One worker is created at a time, concurrent calls could go past max_workers otherwise
+		let _creation = self.worker_creation.lock().await;
+		if self.workers.lock().len() >= self.max_workers {
+			return None;
+		}
+
+		let worker_maybe = self.worker_manager.create_worker({
+			let mut settings = WorkerSettings::default();
+			settings.log_tags = vec![
+				WorkerLogTag::Info,
+				WorkerLogTag::Ice,
+				WorkerLogTag::Dtls,
+				WorkerLogTag::Rtp,
+				WorkerLogTag::Rtcp,
+				WorkerLogTag::Srtp,
+				WorkerLogTag::Rtx,
+				WorkerLogTag::Bwe,
+				WorkerLogTag::Score,
+				WorkerLogTag::Simulcast,
+				WorkerLogTag::Svc,
+				WorkerLogTag::Sctp,
+				WorkerLogTag::Message
+			];
+			settings
+		}).await;
+
+		match worker_maybe {
+			Ok(worker) => {
+				log::info!("Created new worker {}", worker.id());
+
+				// Create the structure that'll hold the worker alive
+				match WorkerData::new(worker.clone()).await {
+					Ok(worker_data) => {
+						self.workers.lock().push(worker_data);
+						log::debug!("There are {}/{} workers currently", self.workers.lock().len(), self.max_workers);
+
+						let handler = worker.on_dead({
+							let other_self = self.clone();
+							let worker_id = worker.id();
+							// The worker dies on its own thread, recovery must go back to the runtime
+							let runtime = tokio::runtime::Handle::current();
+							move |r| {
+								log::warn!("Worker died for reason {}", r.err().unwrap());
+								other_self.on_worker_dead(worker_id, runtime);
+							}
+						});
+						push_handler(&self.workers, &worker.id(), handler);
+
+						Some(worker)
+					},
+					Err(e) => {
+						log::error!("Failed to create Worker data: {e}");
+						None
 					}
-				},
-				Err(e) => {
-					log::error!("Failed to create Worker: {e}");
 				}
-			};
+			},
+			Err(e) => {
+				log::error!("Failed to create Worker: {e}");
+				None
+			}
 		}
+	}
 
-		match self.workers.lock().iter().min_by(|w1, w2| u32::cmp(&w1.get_num_consumers(), &w2.get_num_consumers())) {
-			None => panic!("Fatal error: Could not create any worker."),
-			Some(worker_data) => worker_data.worker.clone()
+	/// Lets the worker selector choose among existing workers, preferring the ones that aren't excluded and not full
+	fn select_worker(&self, room_id: &RoomId, excluded_workers: &[WorkerId]) -> Worker {
+		let workers = self.workers.lock();
+		if workers.is_empty() {
+			panic!("Fatal error: Could not create any worker.");
 		}
-	}
 
-	/// Release a worker if it's in a dead state
-	fn on_worker_dead(&self, worker_id: WorkerId) {
-		let mut workers = self.workers.lock();
-		match workers
+		let allowed: Vec<&WorkerData> = workers
+			.iter()
+			.filter(|w| !excluded_workers.contains(&w.worker.id()))
+			.collect();
+		let not_full: Vec<&WorkerData> = allowed
+			.iter()
+			.copied()
+			.filter(|w| w.get_num_consumers() < self.consumers_per_worker)
+			.collect();
+
+		let candidates: Vec<&WorkerData> = match (not_full.is_empty(), allowed.is_empty()) {
+			(false, _) => not_full,
+			(true, false) => allowed,
+			(true, true) => workers.iter().collect(),
+		};
+
+		let loads: Vec<WorkerLoad> = candidates
 			.iter()
-			.position(|w| w.worker.id() == worker_id) {
-				Some(index) => {
-					let worker_last_breath = workers.remove(index);
-					if worker_last_breath.consumer_count > 0 {
-						log::warn!("A Worker was terminated early but had participants, this should not happen. Their connections will be closed");
-						worker_last_breath.worker_died_unexpectedly.call_simple();
+			.map(|w| WorkerLoad {
+				worker_id: w.worker.id(),
+				consumer_count: w.get_num_consumers(),
+				router_count: w.router_count,
+			})
+			.collect();
+
+		let index = self.worker_selector.select(&loads, room_id).min(candidates.len() - 1);
+		candidates[index].worker.clone()
+	}
+
+	/// Release a worker if it's in a dead state, spawn its replacement and then let the rooms
+	/// that had a router on it recreate one
+	fn on_worker_dead(&self, worker_id: WorkerId, runtime: tokio::runtime::Handle) {
+		let worker_died_unexpectedly = {
+			let mut workers = self.workers.lock();
+			match workers
+				.iter()
+				.position(|w| w.worker.id() == worker_id) {
+					Some(index) => {
+						let worker_last_breath = workers.remove(index);
+						if worker_last_breath.router_count > 0 {
+							log::warn!("A Worker was terminated early with {} routers, their rooms will be moved to a new worker", worker_last_breath.router_count);
+						}
+						worker_last_breath.worker_died_unexpectedly.clone()
+					},
+					None => {
+						log::error!("A worker is dead but couldn't be found in the workers list");
+						return;
 					}
-				},
-				None => log::error!("A worker is dead but couldn't be found in the workers list")
+			}
 		};
+
+		let other_self = self.clone();
+		runtime.spawn(async move {
+			if let Some(worker) = other_self.create_worker_if_allowed().await {
+				log::info!("Worker {} replaces dead worker {worker_id}", worker.id());
+			}
+			worker_died_unexpectedly.call_simple();
+		});
 	}
 
 	/// Count down the number of active routers, if there is 0, release the worker
@@ -0,0 +1,100 @@
+use std::fs;
+use std::num::{NonZeroU32, NonZeroU8};
+use std::path::Path;
+use mediasoup::prelude::*;
+
+/// Media codecs that can be enabled on the routers without writing a codecs file
+/// AV1 is missing as mediasoup 0.17 doesn't support it yet
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum CodecPreset {
+	Opus,
+	Vp8,
+	/// VP9 profile 0, clients can send it with SVC (e.g. scalabilityMode L3T3)
+	Vp9,
+	/// VP9 profile 2, for 10 bits video
+	Vp9Profile2,
+	/// H264 constrained baseline (profile-level-id 42e01f), the most widely supported one
+	H264,
+	/// H264 main (profile-level-id 4d001f)
+	H264Main,
+	/// H264 high (profile-level-id 640032)
+	H264High,
+}
+
+impl CodecPreset {
+	pub fn capability(&self) -> RtpCodecCapability {
+		match self {
+			CodecPreset::Opus => RtpCodecCapability::Audio {
+				mime_type: MimeTypeAudio::Opus,
+				preferred_payload_type: None,
+				clock_rate: NonZeroU32::new(48000).unwrap(),
+				channels: NonZeroU8::new(2).unwrap(),
+				parameters: RtpCodecParametersParameters::from([("useinbandfec", 1_u32.into())]),
+				rtcp_feedback: vec![RtcpFeedback::TransportCc],
+			},
+			CodecPreset::Vp8 => video_capability(MimeTypeVideo::Vp8, RtpCodecParametersParameters::default()),
+			CodecPreset::Vp9 => video_capability(
+				MimeTypeVideo::Vp9,
+				RtpCodecParametersParameters::from([("profile-id", 0_u32.into())])
+			),
+			CodecPreset::Vp9Profile2 => video_capability(
+				MimeTypeVideo::Vp9,
+				RtpCodecParametersParameters::from([("profile-id", 2_u32.into())])
+			),
+			CodecPreset::H264 => h264_capability("42e01f"),
+			CodecPreset::H264Main => h264_capability("4d001f"),
+			CodecPreset::H264High => h264_capability("640032"),
+		}
+	}
+}
+
+fn video_capability(mime_type: MimeTypeVideo, parameters: RtpCodecParametersParameters) -> RtpCodecCapability {
+	RtpCodecCapability::Video {
+		mime_type,
+		preferred_payload_type: None,
+		clock_rate: NonZeroU32::new(90000).unwrap(),
+		parameters,
+		rtcp_feedback: vec![
+			RtcpFeedback::Nack,
+			RtcpFeedback::NackPli,
+			RtcpFeedback::CcmFir,
+			RtcpFeedback::GoogRemb,
+			RtcpFeedback::TransportCc,
+		]
+	}
+}
+
+fn h264_capability(profile_level_id: &'static str) -> RtpCodecCapability {
+	video_capability(
+		MimeTypeVideo::H264,
+		RtpCodecParametersParameters::from([
+			("packetization-mode", 1_u32.into()),
+			("level-asymmetry-allowed", 1_u32.into()),
+			("profile-level-id", profile_level_id.into()),
+		])
+	)
+}
+
+/// The codecs used when nothing else is configured
+pub fn default_codecs() -> Vec<RtpCodecCapability> {
+	[CodecPreset::Opus, CodecPreset::Vp8]
+		.iter()
+		.map(CodecPreset::capability)
+		.collect()
+}
+
+/// Reads a JSON array of mediasoup RtpCodecCapability, e.g.
+/// `[{"kind": "video", "mimeType": "video/VP9", "clockRate": 90000, "parameters": {"profile-id": 2}, "rtcpFeedback": []}]`
+pub fn load_codecs_file(path: &Path) -> Result<Vec<RtpCodecCapability>, String> {
+	let content = fs::read_to_string(path)
+		.map_err(|e| format!("Failed to read codecs file {}: {e}", path.display()))?;
+
+	let codecs: Vec<RtpCodecCapability> = serde_json::from_str(&content)
+		.map_err(|e| format!("Failed to parse codecs file {}: {e}", path.display()))?;
+
+	if codecs.is_empty() {
+		return Err(format!("Codecs file {} doesn't contain any codec", path.display()));
+	}
+
+	Ok(codecs)
+}
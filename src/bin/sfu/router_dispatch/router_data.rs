@@ -1,7 +1,9 @@
 use mediasoup::prelude::*;
+use mediasoup::worker::WorkerId;
 use event_listener_primitives::{BagOnce, HandlerId};
 use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct RouterData {
 	pub router: Router,
 	pub webrtc_server: WebRtcServer,
@@ -9,6 +11,10 @@ pub struct RouterData {
 }
 
 impl RouterData {
+	pub fn worker_id(&self) -> WorkerId {
+		self.router.worker().id()
+	}
+
 	pub fn on_worker_died_unexpectedly<F>(&self, callback: F) -> HandlerId
 	where F: FnOnce() + Send + Sync + 'static {
 		self.worker_died_unexpectedly.add(Box::new(callback))
@@ -1,6 +1,14 @@
+use mediasoup::prelude::*;
+use super::codecs::default_codecs;
+use super::worker_selection::WorkerSelection;
+
 pub struct RouterDispatchConfig {
 	pub max_workers: usize,
 	pub consumers_per_worker: u32,
+	/// Media codecs enabled on every router
+	pub codecs: Vec<RtpCodecCapability>,
+	/// How a worker is picked for a new router once all of them are created
+	pub worker_selection: WorkerSelection,
 }
 
 impl Default for RouterDispatchConfig {
@@ -8,6 +16,8 @@ impl Default for RouterDispatchConfig {
 		RouterDispatchConfig {
 			max_workers: 1,
 			consumers_per_worker: 500,
+			codecs: default_codecs(),
+			worker_selection: WorkerSelection::default(),
 		}
 	}
 }
@@ -41,7 +41,7 @@ impl WorkerData {
 	}
 
 	pub(super) fn get_num_consumers(&self) -> u32 {
-		unimplemented!()
+		self.consumer_count
 	}
 
 	pub(super) async fn create_webrtc_server(worker: &Worker) -> Result<WebRtcServer, CreateWebRtcServerError> {
@@ -0,0 +1,87 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use confroom_server::uuids::RoomId;
+use mediasoup::worker::WorkerId;
+
+/// Load of a worker at the time a router has to be placed.
+/// mediasoup 0.17 runs workers as threads and doesn't expose their resource usage, so only our own counters are known
+#[derive(Debug, Clone, Copy)]
+pub struct WorkerLoad {
+	pub worker_id: WorkerId,
+	pub consumer_count: u32,
+	pub router_count: u32,
+}
+
+/// Decides which worker a new router goes to, once every allowed worker has been created
+pub trait WorkerSelector: Send + Sync {
+	/// Returns the index in candidates of the chosen worker, candidates is never empty
+	fn select(&self, candidates: &[WorkerLoad], room_id: &RoomId) -> usize;
+}
+
+/// The available worker selection policies
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WorkerSelection {
+	/// The worker with the fewest consumers, then the fewest routers
+	#[default]
+	LeastConsumers,
+	/// Each worker in turn
+	RoundRobin,
+	/// The worker is derived from the room id and the worker ids, so a room keeps going to the same worker as long as it's a candidate
+	RoomAffinity,
+}
+
+impl WorkerSelection {
+	pub fn selector(&self) -> Arc<dyn WorkerSelector> {
+		match self {
+			WorkerSelection::LeastConsumers => Arc::new(LeastConsumers),
+			WorkerSelection::RoundRobin => Arc::new(RoundRobin::default()),
+			WorkerSelection::RoomAffinity => Arc::new(RoomAffinity),
+		}
+	}
+}
+
+pub struct LeastConsumers;
+
+impl WorkerSelector for LeastConsumers {
+	fn select(&self, candidates: &[WorkerLoad], _room_id: &RoomId) -> usize {
+		candidates
+			.iter()
+			.enumerate()
+			.min_by_key(|(_, load)| (load.consumer_count, load.router_count))
+			.map(|(index, _)| index)
+			.unwrap_or_default()
+	}
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+	next: AtomicUsize,
+}
+
+impl WorkerSelector for RoundRobin {
+	fn select(&self, candidates: &[WorkerLoad], _room_id: &RoomId) -> usize {
+		self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()
+	}
+}
+
+pub struct RoomAffinity;
+
+/// Rendezvous hashing: each worker gets a score for the room and the highest one wins,
+/// workers appearing or disappearing only move the rooms that were on them
+impl WorkerSelector for RoomAffinity {
+	fn select(&self, candidates: &[WorkerLoad], room_id: &RoomId) -> usize {
+		candidates
+			.iter()
+			.enumerate()
+			.max_by_key(|(_, load)| {
+				let mut hasher = DefaultHasher::new();
+				room_id.hash(&mut hasher);
+				load.worker_id.hash(&mut hasher);
+				hasher.finish()
+			})
+			.map(|(index, _)| index)
+			.unwrap_or_default()
+	}
+}
@@ -0,0 +1,59 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use confroom_server::uuids::ResumeToken;
+use crate::participant::ParticipantConnection;
+
+struct SuspendedSession {
+	connection: ParticipantConnection,
+	suspended_at: Instant,
+}
+
+/// Keeps participants whose websocket dropped alive for a grace period so they can resume their session
+#[derive(Clone)]
+pub struct SessionsRegistry {
+	sessions: Arc<Mutex<HashMap<ResumeToken, SuspendedSession>>>,
+	grace_period: Duration,
+}
+impl SessionsRegistry {
+	pub fn new(grace_period: Duration) -> Self {
+		SessionsRegistry {
+			sessions: Arc::new(Mutex::new(HashMap::new())),
+			grace_period,
+		}
+	}
+
+	/// Holds on to the connection until it's resumed or the grace period expires.
+	/// With a grace period of zero the connection is dropped right away
+	pub fn suspend(&self, connection: ParticipantConnection) {
+		if self.grace_period.is_zero() {
+			return;
+		}
+
+		let token = connection.resume_token();
+		let suspended_at = Instant::now();
+		self.sessions.lock().insert(token, SuspendedSession { connection, suspended_at });
+
+		tokio::spawn({
+			let sessions = Arc::clone(&self.sessions);
+			let grace_period = self.grace_period;
+			async move {
+				tokio::time::sleep(grace_period).await;
+
+				let mut sessions = sessions.lock();
+				// The session might have been resumed and suspended again since
+				if sessions.get(&token).is_some_and(|session| session.suspended_at == suspended_at) {
+					log::info!("Session {token} expired without being resumed");
+					sessions.remove(&token);
+				}
+			}
+		});
+	}
+
+	/// Takes a suspended session out of the registry so it can be attached to a new websocket
+	pub fn take(&self, token: &ResumeToken) -> Option<ParticipantConnection> {
+		self.sessions.lock().remove(token).map(|session| session.connection)
+	}
+}
@@ -1,28 +1,58 @@
-use std::{env, net::{Ipv4Addr, SocketAddrV4}, sync::Arc};
+use std::{env, net::{Ipv4Addr, SocketAddrV4}, sync::Arc, time::Duration};
 
-use confroom_server::{monitoring::SFUEvent, uuids::RoomId};
+use confroom_server::{monitoring::SFUEvent, uuids::{ResumeToken, RoomId}};
 use parking_lot::Mutex;
 use serde::Deserialize;
-use crate::{monitor_dispatch::MonitorDispatch, participant::ParticipantConnection, router_dispatch::{RouterDispatch, RouterDispatchConfig, ANNOUNCED_ADDRESS_ENV_KEY}};
+use crate::{monitor_dispatch::MonitorDispatch, participant::{BitrateCaps, ConnectionEnd, ParticipantConfig, ParticipantConnection}, router_dispatch::{RouterDispatch, RouterDispatchConfig, ANNOUNCED_ADDRESS_ENV_KEY}};
+use crate::handshake;
+use crate::media_policy::MediaPolicy;
+use crate::message::Encoding;
 use crate::room::Room;
 use crate::rooms_registry::RoomsRegistry;
+use crate::sessions_registry::SessionsRegistry;
 use crate::security::get_tls_mode_settings;
-use warp::{filters::{query::query, ws::{WebSocket, Ws}}, Filter};
+use futures_util::SinkExt;
+use warp::{filters::{query::query, ws::{self, WebSocket, Ws}}, http::StatusCode, Filter};
 
 #[derive(Deserialize)]
 #[serde(rename_all="camelCase")]
 struct QueryParameters {
-	room_id: Option<RoomId>
+	room_id: Option<RoomId>,
+	encoding: Option<Encoding>,
+	/// Consume every producer of the room without waiting for Consume messages
+	auto_subscribe: Option<bool>,
+	display_name: Option<String>,
+	/// Arbitrary JSON describing the participant
+	metadata: Option<String>,
+	/// Token of a previous session to reattach to
+	resume: Option<ResumeToken>,
+	/// JSON MediaPolicy applied if the room gets created by this participant
+	media_policy: Option<String>,
 }
 
+/// Close code sent when a resume token is unknown or its session expired
+const CLOSE_RESUME_FAILED: u16 = 4002;
+/// Close code sent to new participants when every worker reached its consumer limit
+const CLOSE_SERVER_AT_CAPACITY: u16 = 4003;
+/// Close code sent when the mediaPolicy query parameter can't be read
+const CLOSE_INVALID_MEDIA_POLICY: u16 = 4004;
+/// Close code sent when the metadata query parameter can't be read
+const CLOSE_INVALID_METADATA: u16 = 4005;
+
 pub struct SFUServerConfig {
 	pub port: u16,
-	pub router_dispatch_config: Option<RouterDispatchConfig>
+	pub router_dispatch_config: Option<RouterDispatchConfig>,
+	/// How long a participant whose websocket dropped is kept around waiting to resume
+	pub resume_grace_period: Duration,
+	/// Default and highest bitrate caps of every participant
+	pub max_bitrate: BitrateCaps,
 }
 
 pub struct SFUServerRuntime {
 	router_dispatch: RouterDispatch,
 	rooms: RoomsRegistry,
+	sessions: SessionsRegistry,
+	max_bitrate: BitrateCaps,
 }
 
 #[derive(Clone)]
@@ -32,10 +62,12 @@ pub struct SFUServer {
 }
 
 impl SFUServerRuntime {
-	fn new(dispatch_config: RouterDispatchConfig) -> Self {
+	fn new(dispatch_config: RouterDispatchConfig, resume_grace_period: Duration, max_bitrate: BitrateCaps) -> Self {
 		SFUServerRuntime {
 			router_dispatch: RouterDispatch::new(dispatch_config),
 			rooms: RoomsRegistry::new(),
+			sessions: SessionsRegistry::new(resume_grace_period),
+			max_bitrate,
 		}
 	}
 }
@@ -44,7 +76,9 @@ impl Default for SFUServerRuntime {
 	fn default() -> Self {
 		SFUServerRuntime {
 			router_dispatch: RouterDispatch::default(),
-			rooms: RoomsRegistry::new()
+			rooms: RoomsRegistry::new(),
+			sessions: SessionsRegistry::new(SFUServerConfig::default().resume_grace_period),
+			max_bitrate: BitrateCaps::default(),
 		}
 	}
 }
@@ -54,6 +88,8 @@ impl Default for SFUServerConfig {
 		SFUServerConfig {
 			port: 8000,
 			router_dispatch_config: None,
+			resume_grace_period: Duration::from_secs(30),
+			max_bitrate: BitrateCaps::default(),
 		}
 	}
 }
@@ -74,7 +110,9 @@ impl SFUServer {
 			runtime: Arc::new(
 				Mutex::new(
 					SFUServerRuntime::new(
-						config.router_dispatch_config.unwrap_or(RouterDispatchConfig::default()))
+						config.router_dispatch_config.unwrap_or(RouterDispatchConfig::default()),
+						config.resume_grace_period,
+						config.max_bitrate)
 				)
 			)
 		}
@@ -86,16 +124,28 @@ impl SFUServer {
 			move || server.clone()
 		});
 
-	   	let routes = warp::path!("ws")
+	   	let websocket_route = warp::path!("ws")
 	        .and(warp::ws())
 	        .and(query::<QueryParameters>())
-	        .and(with_server_data)
+	        .and(with_server_data.clone())
 	        .map(|ws: Ws, query_parameters: QueryParameters, server: SFUServer| {
 	        	ws.on_upgrade(move |websocket| {
 	         		handle_websocket(websocket, query_parameters, server)
 	         	})
 	    });
 
+		// Lets a load balancer know whether new participants are accepted
+		let capacity_route = warp::path!("capacity")
+			.and(warp::get())
+			.and(with_server_data)
+			.map(|server: SFUServer| {
+				let capacity = server.runtime.lock().router_dispatch.capacity();
+				let status = if capacity.headroom > 0 { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+				warp::reply::with_status(warp::reply::json(&capacity), status)
+			});
+
+		let routes = websocket_route.or(capacity_route);
+
 	    let socket_addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), self.port);
 
 		let _ = MonitorDispatch::send_event(SFUEvent::ServerStarted);
@@ -122,23 +172,76 @@ impl SFUServer {
 	}
 }
 
-async fn handle_websocket(websocket: WebSocket, query_parameters: QueryParameters, server: SFUServer) {
+async fn handle_websocket(mut websocket: WebSocket, query_parameters: QueryParameters, server: SFUServer) {
 
-	let router_dispatch = server.runtime.lock().router_dispatch.clone();
-	let router_data = match router_dispatch.create_router().await {
-		Ok(data) => data,
+	// Nothing gets allocated for a client speaking a protocol we don't understand
+	let handshake = match handshake::negotiate(&mut websocket, query_parameters.encoding.unwrap_or_default()).await {
+		Ok(handshake) => handshake,
 		Err(e) => {
-			log::error!("Failed to create router, no room will be fetched/created: {e}");
-			// Return early, don't create any connection
+			log::warn!("Handshake failed, closing connection: {e}");
 			return;
 		}
 	};
+	log::debug!("Negotiated protocol version {} with features {:?} using {:?} encoding",
+		handshake.protocol_version, handshake.features, handshake.encoding);
+
+	let sessions = server.runtime.lock().sessions.clone();
+
+	if let Some(token) = query_parameters.resume {
+		match sessions.take(&token) {
+			Some(conn) => {
+				if conn.resume(websocket, handshake.encoding, handshake.features).await == ConnectionEnd::Dropped {
+					sessions.suspend(conn);
+				}
+			},
+			None => {
+				log::info!("Unknown or expired resume token {token}");
+				let _ = websocket.send(ws::Message::close_with(CLOSE_RESUME_FAILED, "Unknown or expired resume token")).await;
+			}
+		}
+		return;
+	}
+
+	// Falling back to the default policy would silently lift the restrictions the room was meant to have
+	let media_policy: MediaPolicy = match query_parameters.media_policy.map(|media_policy| serde_json::from_str(&media_policy)) {
+		Some(Ok(media_policy)) => media_policy,
+		Some(Err(e)) => {
+			log::warn!("Refused a participant with an invalid media policy: {e}");
+			let _ = websocket.send(ws::Message::close_with(CLOSE_INVALID_MEDIA_POLICY, format!("Invalid media policy: {e}"))).await;
+			return;
+		},
+		None => MediaPolicy::default(),
+	};
+
+	let metadata = match (handshake.metadata, query_parameters.metadata) {
+		(Some(metadata), _) => metadata,
+		(None, Some(metadata)) => match serde_json::from_str(&metadata) {
+			Ok(metadata) => metadata,
+			Err(e) => {
+				log::warn!("Refused a participant with invalid metadata: {e}");
+				let _ = websocket.send(ws::Message::close_with(CLOSE_INVALID_METADATA, format!("Invalid metadata: {e}"))).await;
+				return;
+			}
+		},
+		(None, None) => serde_json::Value::Null,
+	};
+
+	let (rooms, router_dispatch) = {
+		let runtime = server.runtime.lock();
+		(runtime.rooms.clone(), runtime.router_dispatch.clone())
+	};
+
+	// Resumed sessions are already accounted for, only new participants are turned away
+	if router_dispatch.capacity().headroom == 0 {
+		log::warn!("Refused a new participant, the server is at capacity");
+		let _ = websocket.send(ws::Message::close_with(CLOSE_SERVER_AT_CAPACITY, "The server is at capacity")).await;
+		return;
+	}
 
 	let room: Room = {
-		let rooms = server.runtime.lock().rooms.clone();
-		let room_maybe = match query_parameters.room_id.clone() {
-			Some(room_id) => rooms.get_or_create(room_id, router_data).await,
-			None => rooms.create_room(router_data).await
+		let room_maybe = match query_parameters.room_id {
+			Some(room_id) => rooms.get_or_create(room_id, &router_dispatch, media_policy).await,
+			None => rooms.create_room(&router_dispatch, media_policy).await
 		};
 
 		match room_maybe {
@@ -151,8 +254,20 @@ async fn handle_websocket(websocket: WebSocket, query_parameters: QueryParameter
 		}
 	};
 
-	match ParticipantConnection::new(room).await {
-		Ok(conn) => conn.run(websocket).await,
+	let participant_config = ParticipantConfig {
+		auto_subscribe: query_parameters.auto_subscribe.unwrap_or(false),
+		display_name: handshake.display_name.or(query_parameters.display_name),
+		metadata,
+		max_bitrate: server.runtime.lock().max_bitrate,
+	};
+
+	match ParticipantConnection::new(room, participant_config).await {
+		Ok(conn) => {
+			// Keep the participant around if its websocket dropped unexpectedly
+			if conn.run(websocket, handshake.encoding, handshake.features).await == ConnectionEnd::Dropped {
+				sessions.suspend(conn);
+			}
+		},
 		Err(e) => {
 			log::error!("{e}");
 			eprintln!("Error creating participant connection");
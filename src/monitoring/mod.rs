@@ -44,5 +44,9 @@ pub enum SFUEvent {
 		room_id: RoomId,
 		participant_id: ParticipantId
 	},
+	DominantSpeaker {
+		room_id: RoomId,
+		participant_id: ParticipantId
+	},
 	Error(SFUErrorReturn)
 }
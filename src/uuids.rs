@@ -41,3 +41,17 @@ impl std::fmt::Display for MonitorId {
 		std::fmt::Display::fmt(&self.0, f)
 	}
 }
+
+/// Secret handed to a participant so it can reattach to its session after losing its websocket
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Deserialize, Serialize, Copy)]
+pub struct ResumeToken(Uuid);
+impl ResumeToken {
+	pub fn new() -> Self {
+		ResumeToken(Uuid::new_v4())
+	}
+}
+impl std::fmt::Display for ResumeToken {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		std::fmt::Display::fmt(&self.0, f)
+	}
+}